@@ -0,0 +1,148 @@
+//! Send throughput over loopback, comparing GSO batches against one datagram per transmit.
+//!
+//! Run with `cargo bench --bench loopback`. A receiver thread drains the other end, so the
+//! received rate shows how much of what we sent actually made it through.
+
+// Only some of each module is used here, and their tests are compiled out but not their imports
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/outbound.rs"]
+mod outbound;
+#[path = "../src/socket.rs"]
+mod socket;
+#[path = "../src/util.rs"]
+mod util;
+
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Interest, Poll, Token};
+use quinn_proto::Transmit;
+
+use crate::outbound::Outbound;
+use crate::socket::{Socket, SocketConfig};
+
+const SEGMENT_SIZE: usize = 1200;
+const SEGMENTS: usize = 10;
+const TRANSMITS_PER_SEND: usize = 32;
+const DURATION: Duration = Duration::from_secs(2);
+
+fn main() {
+    let config = SocketConfig {
+        recv_buffer_size: Some(4 * 1024 * 1024),
+        send_buffer_size: Some(4 * 1024 * 1024),
+        ..Default::default()
+    };
+
+    run("single", None, &config);
+    run("gso", Some(SEGMENT_SIZE), &config);
+}
+
+/// Sends as fast as the socket allows for `DURATION`, then reports the send and receive rates.
+fn run(name: &str, segment_size: Option<usize>, config: &SocketConfig) {
+    let receiver = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    _ = socket2::SockRef::from(&receiver).set_recv_buffer_size(16 * 1024 * 1024);
+    let destination = receiver.local_addr().unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let received = Arc::new(AtomicU64::new(0));
+    let drain = {
+        let (done, received) = (done.clone(), received.clone());
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while done.load(Ordering::Relaxed) == false {
+                if let Ok(len) = receiver.recv(&mut buf) {
+                    received.fetch_add(len as u64, Ordering::Relaxed);
+                }
+            }
+        })
+    };
+
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(8);
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut socket = Socket::new(addr, SEGMENT_SIZE, config).unwrap();
+    poll.registry()
+        .register(&mut socket, Token(0), Interest::READABLE)
+        .unwrap();
+
+    // The same layout quinn-proto produces: full segments back to back in one buffer
+    let size = SEGMENT_SIZE * SEGMENTS;
+    let mut outbound = Outbound::new(size);
+    let payload = vec![0xab; size];
+
+    let mut sent = 0u64;
+    let mut errors = 0u64;
+    let start = Instant::now();
+    while start.elapsed() < DURATION {
+        for _ in 0..TRANSMITS_PER_SEND {
+            match segment_size {
+                Some(segment_size) => {
+                    push(&mut outbound, destination, &payload, Some(segment_size));
+                    sent += size as u64;
+                }
+                None => {
+                    for segment in payload.chunks(SEGMENT_SIZE) {
+                        push(&mut outbound, destination, segment, None);
+                    }
+                    sent += size as u64;
+                }
+            }
+        }
+
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |_| errors += 1)
+            .unwrap();
+
+        // Anything still queued waits for the socket to become writable, like in the server
+        while socket.pending_sends() > 0 {
+            poll.poll(&mut events, Some(Duration::from_millis(10)))
+                .unwrap();
+            socket
+                .flush(poll.registry(), &mut outbound, 0, |_| errors += 1)
+                .unwrap();
+        }
+    }
+
+    let elapsed = start.elapsed();
+    thread::sleep(Duration::from_millis(200)); // Let the receiver catch up
+    done.store(true, Ordering::Relaxed);
+    drain.join().unwrap();
+
+    let stats = socket.send_stats();
+    let mbps = |bytes: u64| bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0;
+    println!(
+        "{:>6}: sent {:>7.1} Mbit/s, received {:>7.1} Mbit/s ({} deferred, {} dropped, {} errors)",
+        name,
+        mbps(sent),
+        mbps(received.load(Ordering::Relaxed)),
+        stats.deferred,
+        stats.dropped,
+        errors,
+    );
+}
+
+fn push(
+    outbound: &mut Outbound,
+    destination: SocketAddr,
+    contents: &[u8],
+    segment_size: Option<usize>,
+) {
+    let mut buf = outbound.take_buffer();
+    buf.extend_from_slice(contents);
+
+    let transmit = Transmit {
+        destination,
+        ecn: None,
+        size: contents.len(),
+        segment_size,
+        src_ip: None,
+    };
+    outbound.push(0, None, transmit, buf);
+}
@@ -1,43 +1,162 @@
 mod outbound;
 mod server;
 mod session;
+mod shard;
 mod socket;
 mod util;
 
 mod webtransport;
 
+use std::env;
 use std::fs::File;
 use std::io::{BufReader, ErrorKind};
 use std::net::SocketAddr;
-use std::str;
+use std::str::{self, FromStr};
+use std::thread;
 use std::time::Instant;
 
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Registry, Token};
+use quinn_proto::{ConnectionHandle, StreamId};
 
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
 use crate::server::Server;
-use crate::socket::Socket;
+use crate::session::Session;
+use crate::shard::ShardLink;
+use crate::socket::{SentTransmit, Socket, SocketConfig};
+use crate::webtransport::WebTransportConfig;
 
-const TOKEN_RECV: Token = Token(0);
+/// Wakes a shard up when another shard has forwarded it packets.
+const TOKEN_SHARD: Token = Token(usize::MAX);
+
+/// A WebTransport bidirectional stream whose data we're echoing back to the client.
+struct Echo {
+    handle: ConnectionHandle,
+    id: StreamId,
+    buf: Vec<u8>,
+    finished: bool,
+}
 
 fn main() {
     //simple_logger::init().unwrap();
 
     let (certs, key) = read_certs();
-    let mut server = Server::new(certs, key).expect("failed to create server");
 
-    let addr: SocketAddr = "127.0.0.1:4443".parse().unwrap();
-    let mut socket = Socket::new(addr, server.get_max_udp_payload_size() as usize);
+    // Takes the addresses to listen on, and optionally `--shards <count>` to run multi-threaded.
+    // Socket options keep the kernel's defaults unless given: `--recv-buffer` and `--send-buffer`
+    // in bytes, `--dscp` as a codepoint, and `--busy-poll` in microseconds.
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    let mut shards = 1;
+    let mut socket_config = SocketConfig {
+        only_v6: Some(false),
+        ..Default::default()
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--shards" => shards = parse_value(&arg, args.next()),
+            "--recv-buffer" => {
+                socket_config.recv_buffer_size = Some(parse_value(&arg, args.next()))
+            }
+            "--send-buffer" => {
+                socket_config.send_buffer_size = Some(parse_value(&arg, args.next()))
+            }
+            "--dscp" => socket_config.dscp = Some(parse_value(&arg, args.next())),
+            "--busy-poll" => socket_config.busy_poll = Some(parse_value(&arg, args.next())),
+            _ => addrs.push(arg.parse().expect("invalid listen address")),
+        }
+    }
+
+    // Listen dual-stack on the default port if nothing was given, so IPv4 clients work too
+    if addrs.is_empty() {
+        addrs.push("[::]:4443".parse().unwrap());
+    }
+
+    if shards <= 1 {
+        let server =
+            Server::new(certs, key, webtransport_config()).expect("failed to create server");
+        run(server, Poll::new().unwrap(), &addrs, socket_config, None);
+        return;
+    }
+
+    // Every shard gets its own thread, endpoint and sockets, all bound to the same addresses
+    let polls: Vec<Poll> = (0..shards).map(|_| Poll::new().unwrap()).collect();
+    let registries: Vec<&Registry> = polls.iter().map(Poll::registry).collect();
+    let links = ShardLink::link_all(&registries, TOKEN_SHARD).expect("failed to link shards");
+
+    let workers: Vec<_> = polls
+        .into_iter()
+        .zip(links)
+        .map(|(poll, link)| {
+            let config = webtransport_config();
+            let server = Server::new_shard(certs.clone(), key.clone_key(), config, link.shard())
+                .expect("failed to create server");
+            let addrs = addrs.clone();
+            let socket_config = SocketConfig {
+                reuse_port: true, // Shards share the same addresses
+                ..socket_config.clone()
+            };
+
+            thread::spawn(move || run(server, poll, &addrs, socket_config, Some(link)))
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().expect("shard panicked");
+    }
+}
+
+/// Parses the value following a command line option, panicking if it's missing or invalid.
+fn parse_value<T: FromStr>(option: &str, value: Option<String>) -> T {
+    match value.map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        _ => panic!("invalid or missing value for {}", option),
+    }
+}
+
+fn webtransport_config() -> WebTransportConfig {
+    let mut config = WebTransportConfig::default();
+    config.cert_hash_path = Some("/cert-hash".to_string());
+    config
+}
+
+/// Runs a server's event loop, forwarding packets for other shards' connections if sharded.
+fn run(
+    mut server: Server,
+    mut poll: Poll,
+    addrs: &[SocketAddr],
+    socket_config: SocketConfig,
+    mut link: Option<ShardLink>,
+) {
+    server.set_packet_timestamps(true);
+
+    let max_udp_payload_size = server.get_max_udp_payload_size() as usize;
 
-    let mut poll = Poll::new().unwrap();
     let mut events = Events::with_capacity(64);
+    let mut stats = server.stats();
+    let mut forward_dropped = 0;
+    let mut echoes: Vec<Echo> = Vec::new();
+
+    // Each socket is registered under its index, which is also how the server tells them apart
+    let mut sockets = Vec::new();
+    for (index, addr) in addrs.iter().enumerate() {
+        let mut socket = Socket::new(*addr, max_udp_payload_size, &socket_config)
+            .expect("failed to create socket");
+        match socket.options() {
+            Ok(options) => println!("socket options for {}: {:?}", addr, options),
+            Err(e) => println!("failed to read socket options: {:?}", e),
+        }
+        if let Err(e) = socket.set_tx_timestamping(true) {
+            println!("tx timestamping unavailable: {:?}", e);
+        }
 
-    poll.registry()
-        .register(&mut socket, TOKEN_RECV, Interest::READABLE)
-        .unwrap();
+        poll.registry()
+            .register(&mut socket, Token(index), Interest::READABLE)
+            .unwrap();
 
-    println!("listening on {}...", addr);
+        println!("listening on {}...", addr);
+        sockets.push(socket);
+    }
 
     loop {
         let now = Instant::now();
@@ -54,27 +173,102 @@ fn main() {
 
         let now = Instant::now();
         for event in events.iter() {
-            match event.token() {
-                TOKEN_RECV => {
-                    match socket.recv_all(|bytes, meta| {
-                        println!("recv: {}B", bytes.len());
-                        server.handle_recv(now, bytes, meta)
-                    }) {
-                        Ok(()) => {}
-                        Err(e) => println!("recv error: {:?}", e),
-                    }
+            if event.token() == TOKEN_SHARD {
+                // Packets another shard received for one of our connections
+                while let Some(packet) = link.as_ref().and_then(ShardLink::recv) {
+                    server.handle_recv(now, packet.socket, packet.data, &packet.meta);
+                }
+                continue;
+            }
+
+            let Token(index) = event.token();
+            let socket = &mut sockets[index];
+
+            if event.is_writable() {
+                let flush = socket.flush(poll.registry(), server.outbound(), index, |e| {
+                    println!("send error: {:?}", e);
+                });
+                match flush {
+                    Ok(sent) => log_sent(sent),
+                    Err(e) => println!("flush error: {:?}", e),
+                }
+            }
+
+            match socket.recv_all(|bytes, meta| {
+                println!("recv: {}B", bytes.len());
+
+                // If sharded, the packet may belong to another shard's connection
+                let bytes = match &mut link {
+                    Some(link) => link.forward(index, bytes, meta),
+                    None => Some(bytes),
+                };
+                if let Some(bytes) = bytes {
+                    server.handle_recv(now, index, bytes, meta)
                 }
-                _ => unreachable!(),
+            }) {
+                Ok(()) => {}
+                Err(e) => println!("recv error: {:?}", e),
+            }
+
+            // Transmit timestamps arrive on the error queue, which also wakes us up
+            if let Err(e) = socket.recv_tx_timestamps(|tx| {
+                println!("sent #{} at {:?}", tx.id, tx.timestamp);
+            }) {
+                println!("tx timestamp error: {:?}", e);
             }
         }
 
         server.handle_process(now);
 
+        if server.stats() != stats {
+            stats = server.stats();
+            println!("server stats: {:?}", stats);
+        }
+
+        let dropped = link.as_ref().map_or(0, ShardLink::dropped);
+        if dropped != forward_dropped {
+            forward_dropped = dropped;
+            println!(
+                "dropped {} packets forwarded to full shards",
+                forward_dropped
+            );
+        }
+
         // Get all the datagrams and do stuff with them
         for (connection_handle, session) in server.sessions_mut() {
+            while let Some(event) = session.poll_event() {
+                println!("got event {:?} from {:?}", event, connection_handle);
+            }
+
+            // We have no use for what's sent on uni streams, so ask the client to stop sending
+            while let Some(stream_id) = session.accept_uni() {
+                println!(
+                    "accepted uni stream {:?} from {:?}",
+                    stream_id, connection_handle
+                );
+                _ = session.stop_stream(stream_id, 0);
+            }
+
+            while let Some(stream_id) = session.accept_bi() {
+                println!(
+                    "accepted bi stream {:?} from {:?}",
+                    stream_id, connection_handle
+                );
+                echoes.push(Echo {
+                    handle: *connection_handle,
+                    id: stream_id,
+                    buf: Vec::new(),
+                    finished: false,
+                });
+            }
+
+            echoes.retain_mut(|echo| {
+                echo.handle != *connection_handle || update_echo(session, echo) == false
+            });
+
             loop {
-                let bytes = match session.recv_datagram() {
-                    Ok(Some(bytes)) => bytes,
+                let datagram = match session.recv_datagram() {
+                    Ok(Some(datagram)) => datagram,
                     Ok(None) => break,
 
                     Err(e) => {
@@ -83,10 +277,12 @@ fn main() {
                     }
                 };
 
+                let bytes = datagram.payload;
                 println!(
-                    "echoing datagram '{}' from {:?}",
+                    "echoing datagram '{}' from {:?} (received at {:?})",
                     str::from_utf8(&bytes).unwrap(),
                     connection_handle,
+                    datagram.timestamp,
                 );
 
                 let send = session.send_datagram(|buf| {
@@ -101,17 +297,90 @@ fn main() {
             }
         }
 
-        // Send all the outgoing traffic
-        for (transmit, buffer) in server.outgoing() {
-            println!("send: {}B", buffer.len());
-            match socket.try_send(transmit, buffer) {
-                Ok(()) => (),
+        // Streams go away along with their connection
+        echoes.retain(|echo| {
+            server
+                .sessions_mut()
+                .any(|(handle, _)| *handle == echo.handle)
+        });
+
+        // Send all the outgoing traffic, each through the socket its connection arrived on
+        for (index, socket) in sockets.iter_mut().enumerate() {
+            let send = socket.send_all(poll.registry(), server.outbound(), index, |e| {
+                println!("send error: {:?}", e);
+            });
+            match send {
+                Ok(sent) => log_sent(sent),
                 Err(e) => println!("send error: {:?}", e),
             }
         }
     }
 }
 
+/// Echoes whatever has arrived on a bi stream, returning true once the stream is done with.
+fn update_echo(session: &mut Session, echo: &mut Echo) -> bool {
+    match session.received_reset(echo.id) {
+        Ok(None) => {}
+        Ok(Some(code)) => {
+            // Nothing more is coming, so there's nothing more to echo
+            println!("bi stream {:?} reset with {}", echo.id, code);
+            _ = session.reset_stream(echo.id, code);
+            return true;
+        }
+        Err(e) => println!("bi stream {:?} reset: {:?}", echo.id, e),
+    }
+
+    match session.stopped(echo.id) {
+        Ok(None) => {}
+        Ok(Some(code)) => {
+            // The client doesn't want the echo anymore, so stop sending it as well
+            println!("bi stream {:?} stopped with {}", echo.id, code);
+            _ = session.stop_stream(echo.id, code);
+            return true;
+        }
+        Err(e) => println!("bi stream {:?} stopped: {:?}", echo.id, e),
+    }
+
+    match session.read_stream(echo.id, &mut echo.buf) {
+        Ok(finished) => echo.finished |= finished,
+        Err(e) => {
+            println!("failed to read bi stream {:?}: {:?}", echo.id, e);
+            _ = session.reset_stream(echo.id, 0);
+            return true;
+        }
+    }
+
+    match session.write_stream(echo.id, &echo.buf) {
+        Ok(written) => _ = echo.buf.drain(..written),
+        Err(e) => {
+            println!("failed to write bi stream {:?}: {:?}", echo.id, e);
+            _ = session.stop_stream(echo.id, 0);
+            return true;
+        }
+    }
+
+    if echo.finished && echo.buf.is_empty() {
+        _ = session.finish_stream(echo.id);
+        return true;
+    }
+
+    false
+}
+
+/// Logs which TX timestamp ids each transmit went out under, to match up with `sent #<id>`.
+fn log_sent(sent: &[SentTransmit]) {
+    for sent in sent {
+        println!(
+            "sent {}B to {} for {:?} as #{}..#{}",
+            sent.size,
+            sent.destination,
+            sent.connection,
+            sent.tx_ids.first,
+            sent.tx_ids.first.wrapping_add(sent.tx_ids.count - 1),
+        );
+    }
+}
+
 fn read_certs() -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
     rustls::crypto::ring::default_provider()
         .install_default()
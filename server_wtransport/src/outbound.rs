@@ -1,47 +1,134 @@
-use bytes::Bytes;
-use quinn_proto::Transmit;
+use std::vec::Drain;
 
-pub(crate) struct Outbound(pub(crate) Vec<(Transmit, Bytes)>);
+use quinn_proto::{ConnectionHandle, Transmit};
+
+/// The most memory we'll hold on to in spare buffers, past which returned buffers are freed.
+const MAX_POOLED_BYTES: usize = 4 * 1024 * 1024;
+
+/// A transmit, the buffer it was written into, and the connection it belongs to (if any).
+pub(crate) type Queued = (Transmit, Vec<u8>, Option<ConnectionHandle>);
+
+/// Transmits waiting to be sent, and a pool of buffers to write new ones into.
+///
+/// `poll_transmit` writes straight into a pooled buffer, and the socket hands the buffer back
+/// once it's been sent, so the send path stops allocating once the pool has warmed up.
+///
+/// Transmits are queued per socket (by the index the server was given for it), so that each one
+/// leaves through the socket its connection arrived on.
+pub(crate) struct Outbound {
+    transmits: Vec<Vec<Queued>>, // Indexed by socket
+    pool: Vec<Vec<u8>>,
+    pooled_bytes: usize, // The total capacity of the buffers in the pool
+    buffer_size: usize,
+}
 
 impl Outbound {
-    pub(crate) fn new() -> Self {
-        Self(Vec::new())
-    }
-
-    /// Pushes a `Transmit` to the outbound buffer, splitting it if necessary.
-    pub(crate) fn push(&mut self, transmit: Transmit, buf: &mut Vec<u8>) {
-        let mut buffer = Bytes::copy_from_slice(&buf[..transmit.size]);
-
-        match transmit.segment_size {
-            // No separate segments -- just push the whole thing
-            None => self.0.push((transmit, buffer)),
-
-            // Multiple segments, so split and push them as sub-transmits
-            Some(segment_size) => {
-                while buffer.is_empty() == false {
-                    let end = segment_size.min(buffer.len());
-                    let contents = buffer.split_to(end);
-
-                    self.0.push((
-                        Transmit {
-                            destination: transmit.destination,
-                            size: contents.len(),
-                            ecn: transmit.ecn,
-                            segment_size: None,
-                            src_ip: transmit.src_ip,
-                        },
-                        contents,
-                    ));
-                }
+    /// New buffers are allocated with room for `buffer_size` bytes, which should fit the largest
+    /// batch of packets `poll_transmit` writes in one go.
+    pub(crate) fn new(buffer_size: usize) -> Self {
+        Self {
+            transmits: Vec::new(),
+            pool: Vec::new(),
+            pooled_bytes: 0,
+            buffer_size,
+        }
+    }
+
+    /// Takes an empty buffer from the pool, only allocating if the pool has run dry.
+    pub(crate) fn take_buffer(&mut self) -> Vec<u8> {
+        match self.pool.pop() {
+            Some(buf) => {
+                self.pooled_bytes -= buf.capacity();
+                buf
             }
-        };
+            None => Vec::with_capacity(self.buffer_size),
+        }
+    }
+
+    /// Returns a buffer to the pool once nothing needs its contents anymore.
+    ///
+    /// Buffers that grew past the usual size are freed rather than pooled, as is anything that
+    /// would take the pool over its memory limit.
+    pub(crate) fn recycle(&mut self, mut buf: Vec<u8>) {
+        let capacity = buf.capacity();
+        if capacity <= self.buffer_size && self.pooled_bytes + capacity <= MAX_POOLED_BYTES {
+            buf.clear();
+            self.pooled_bytes += capacity;
+            self.pool.push(buf);
+        }
+    }
+
+    /// Queues a `Transmit` along with the buffer it was written into, keeping any GSO segments
+    /// together.
+    ///
+    /// The socket sends the whole batch with a single syscall, or splits it if it has to. The
+    /// `connection` it came from (`None` for the endpoint itself) is reported once it's sent.
+    pub(crate) fn push(
+        &mut self,
+        socket: usize,
+        connection: Option<ConnectionHandle>,
+        transmit: Transmit,
+        mut buf: Vec<u8>,
+    ) {
+        buf.truncate(transmit.size);
+        self.queue(socket).push((transmit, buf, connection));
+    }
 
-        buf.clear();
+    /// Takes every transmit queued for a socket, oldest first.
+    pub(crate) fn drain(&mut self, socket: usize) -> Drain<'_, Queued> {
+        self.queue(socket).drain(..)
+    }
+
+    fn queue(&mut self, socket: usize) -> &mut Vec<Queued> {
+        if socket >= self.transmits.len() {
+            self.transmits.resize_with(socket + 1, Vec::new);
+        }
+
+        &mut self.transmits[socket]
     }
 }
 
-impl Default for Outbound {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_recycled_buffers() {
+        let mut outbound = Outbound::new(1024);
+        let mut buf = outbound.take_buffer();
+        assert_eq!(buf.capacity(), 1024);
+
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        outbound.recycle(buf);
+        assert_eq!(outbound.pooled_bytes, 1024);
+
+        let buf = outbound.take_buffer();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+        assert_eq!(outbound.pooled_bytes, 0);
+    }
+
+    #[test]
+    fn frees_buffers_that_grew() {
+        let mut outbound = Outbound::new(1024);
+        outbound.recycle(Vec::with_capacity(64 * 1024));
+        assert!(outbound.pool.is_empty());
+        assert_eq!(outbound.pooled_bytes, 0);
+    }
+
+    #[test]
+    fn caps_the_pool_by_bytes() {
+        let buffer_size = 64 * 1024;
+        let mut outbound = Outbound::new(buffer_size);
+        let buffers: Vec<_> = (0..MAX_POOLED_BYTES / buffer_size + 10)
+            .map(|_| outbound.take_buffer())
+            .collect();
+        for buf in buffers {
+            outbound.recycle(buf);
+        }
+
+        assert_eq!(outbound.pool.len(), MAX_POOLED_BYTES / buffer_size);
+        assert!(outbound.pooled_bytes <= MAX_POOLED_BYTES);
     }
 }
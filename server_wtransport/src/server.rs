@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -6,15 +6,16 @@ use bytes::{Bytes, BytesMut};
 use quinn_proto::crypto::rustls::QuicServerConfig;
 use quinn_proto::{
     AckFrequencyConfig, ConnectionError, ConnectionHandle, DatagramEvent, Endpoint, EndpointConfig,
-    EndpointEvent, IdleTimeout, Incoming, ServerConfig, Transmit, VarInt,
+    EndpointEvent, IdleTimeout, Incoming, MtuDiscoveryConfig, ServerConfig, VarInt,
 };
 use quinn_udp::RecvMeta;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
 use crate::outbound::Outbound;
-use crate::session::Session;
+use crate::session::{MAX_DATAGRAMS, Session};
+use crate::shard::Shard;
 use crate::util;
-use crate::webtransport::Request;
+use crate::webtransport::{HttpResponse, Request, WebTransportConfig};
 
 /// The HTTP/3 ALPN is required when negotiating a QUIC connection.
 pub const ALPN: &[u8] = b"h3";
@@ -25,20 +26,61 @@ const MAX_ACK_DELAY: Duration = Duration::from_millis(50);
 /// Max idle timeout for clients.
 const MAX_IDLE_TIMEOUT_MS: VarInt = VarInt::from_u32(10_000);
 
+/// The largest MTU discovery will try (quinn's default), which bounds every packet we send.
+const MAX_MTU: u16 = 1452;
+
+/// Counters for notable server events, for monitoring.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections closed for not completing WebTransport setup before the deadline.
+    pub setup_timeouts: u64,
+}
+
 pub struct Server {
     endpoint: Endpoint,
     outbound: Outbound,
     connections: HashMap<ConnectionHandle, Session>,
     endpoint_events: Vec<(ConnectionHandle, EndpointEvent)>,
+    config: Arc<WebTransportConfig>,
+    stats: ServerStats,
 
-    buf: Vec<u8>, // Reusable byte buffer to save on allocations
+    packet_timestamps: bool,
+    last_now: Instant,
 }
 
 impl Server {
     pub fn new(
         certs: Vec<CertificateDer<'static>>,
         key: PrivateKeyDer<'static>,
+        config: WebTransportConfig,
     ) -> Result<Self, rustls::Error> {
+        Self::with_endpoint_config(certs, key, config, EndpointConfig::default())
+    }
+
+    /// Creates one shard of a sharded server, whose connection IDs identify the shard so that
+    /// packets reaching the wrong one can be forwarded (see `ShardLink`).
+    pub fn new_shard(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        config: WebTransportConfig,
+        shard: Shard,
+    ) -> Result<Self, rustls::Error> {
+        let mut endpoint_config = EndpointConfig::default();
+        endpoint_config.cid_generator(shard.cid_generator());
+        Self::with_endpoint_config(certs, key, config, endpoint_config)
+    }
+
+    fn with_endpoint_config(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        mut config: WebTransportConfig,
+        endpoint_config: EndpointConfig,
+    ) -> Result<Self, rustls::Error> {
+        if let Some(path) = config.cert_hash_path.clone() {
+            let body = Bytes::from(cert_hashes_json(&certs));
+            config.add_route(path, move |_| HttpResponse::json(body.clone()));
+        }
+
         let mut server_config =
             rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
                 .with_no_client_auth()
@@ -46,7 +88,6 @@ impl Server {
 
         server_config.alpn_protocols = vec![ALPN.to_vec()]; // Must set the proper protocol
 
-        let endpoint_config = EndpointConfig::default();
         let server_config: QuicServerConfig = server_config.try_into().unwrap();
         let mut server_config = ServerConfig::with_crypto(Arc::new(server_config));
         let mut ack_frequency_config = AckFrequencyConfig::default();
@@ -56,6 +97,13 @@ impl Server {
         transport_config.max_idle_timeout(Some(IdleTimeout::from(MAX_IDLE_TIMEOUT_MS)));
         transport_config.allow_spin(true);
 
+        let mut mtu_discovery_config = MtuDiscoveryConfig::default();
+        mtu_discovery_config.upper_bound(MAX_MTU);
+        transport_config.mtu_discovery_config(Some(mtu_discovery_config));
+
+        // Enough for the largest batch of packets a single poll_transmit can produce
+        let buffer_size = MAX_MTU as usize * MAX_DATAGRAMS;
+
         let server = Server {
             endpoint: Endpoint::new(
                 Arc::new(endpoint_config),
@@ -63,10 +111,13 @@ impl Server {
                 true,
                 None,
             ),
-            outbound: Outbound::new(),
+            outbound: Outbound::new(buffer_size),
             connections: HashMap::new(),
             endpoint_events: Vec::new(),
-            buf: Vec::new(),
+            config: Arc::new(config),
+            stats: ServerStats::default(),
+            packet_timestamps: false,
+            last_now: Instant::now(),
         };
 
         Ok(server)
@@ -76,43 +127,66 @@ impl Server {
         self.endpoint.config().get_max_udp_payload_size()
     }
 
-    pub fn handle_recv(&mut self, now: Instant, data: BytesMut, meta: &RecvMeta) {
-        self.prepare_response_buf();
+    /// Whether to use each packet's receive timestamp, rather than the `now` given to
+    /// `handle_recv`, as the time the packet arrived.
+    ///
+    /// This keeps time spent waiting in socket buffers and our own loop out of RTT samples.
+    pub fn set_packet_timestamps(&mut self, enabled: bool) {
+        self.packet_timestamps = enabled;
+    }
+
+    /// Handles a packet that arrived on the given socket.
+    ///
+    /// Sockets are identified by any index the caller likes, and every transmit for a connection
+    /// is queued under the index of the socket the connection first arrived on.
+    pub fn handle_recv(&mut self, now: Instant, socket: usize, data: BytesMut, meta: &RecvMeta) {
+        let now = self.packet_now(now, meta);
+        self.last_now = now;
 
+        let mut buf = self.outbound.take_buffer();
         let event = self.endpoint.handle(
             now,
             meta.addr,
             meta.dst_ip,
             meta.ecn.map(util::proto_ecn),
             data,
-            &mut self.buf,
+            &mut buf,
         );
 
+        // Only a response needs the buffer, otherwise it can go straight back to the pool
+        if let Some(DatagramEvent::Response(transmit)) = event {
+            self.outbound.push(socket, None, transmit, buf);
+            return;
+        }
+        self.outbound.recycle(buf);
+
         match event {
             Some(DatagramEvent::NewConnection(incoming)) => {
-                if let Err(e) = self.try_accept(incoming, now) {
+                if let Err(e) = self.try_accept(incoming, socket, now) {
                     println!("try_accept err: {:?}", e);
                 }
             }
             Some(DatagramEvent::ConnectionEvent(connection_handle, event)) => {
                 if let Some(connection) = self.connections.get_mut(&connection_handle) {
-                    connection.inner.handle_event(event);
+                    connection.handle_event(event, meta.timestamp);
                 } else {
                     println!("missing connection: {:?}", connection_handle);
                 }
             }
-            Some(DatagramEvent::Response(transmit)) => {
-                self.outbound.push(transmit, &mut self.buf);
-            }
             _ => {} // There may be no event to handle
         }
     }
 
     pub fn handle_process(&mut self, now: Instant) {
-        self.prepare_response_buf();
+        self.last_now = now;
 
         for (connection_handle, connection) in &mut self.connections {
-            connection.handle_process(now, &mut self.buf, &mut self.outbound);
+            if connection.handle_setup_deadline(now) {
+                println!("setup timed out: {:?}", connection_handle);
+                self.stats.setup_timeouts += 1;
+            }
+
+            connection.handle_process(now, *connection_handle, &mut self.outbound);
 
             while let Some(event) = connection.inner.poll_endpoint_events() {
                 self.endpoint_events.push((*connection_handle, event));
@@ -136,19 +210,24 @@ impl Server {
         }
     }
 
+    pub fn stats(&self) -> ServerStats {
+        self.stats
+    }
+
     pub fn sessions_mut(&mut self) -> impl Iterator<Item = (&ConnectionHandle, &mut Session)> {
         self.connections.iter_mut()
     }
 
-    pub fn outgoing(&mut self) -> impl Iterator<Item = (Transmit, Bytes)> + '_ {
-        self.outbound.0.drain(..)
+    /// Transmits waiting to be sent, which hand their buffers back to the pool once sent.
+    pub fn outbound(&mut self) -> &mut Outbound {
+        &mut self.outbound
     }
 
     pub fn compute_next_timeout(&mut self) -> Option<Instant> {
         let mut min: Option<Instant> = None;
 
         for (_, connection) in self.connections.iter_mut() {
-            if let Some(timeout) = connection.inner.poll_timeout().as_mut() {
+            if let Some(timeout) = connection.poll_timeout().as_mut() {
                 match min.as_mut() {
                     Some(min) => *min = *min.min(timeout),
                     None => min = Some(*timeout),
@@ -162,12 +241,11 @@ impl Server {
     fn try_accept(
         &mut self,
         incoming: Incoming,
+        socket: usize,
         now: Instant,
     ) -> Result<ConnectionHandle, ConnectionError> {
-        // We don't need the original data and can reuse the buffer here.
-        self.buf.clear();
-
-        let result = self.endpoint.accept(incoming, now, &mut self.buf, None);
+        let mut buf = self.outbound.take_buffer();
+        let result = self.endpoint.accept(incoming, now, &mut buf, None);
 
         match result {
             Ok((connection_handle, connection)) => {
@@ -176,18 +254,23 @@ impl Server {
                     connection_handle,
                     Session {
                         inner: connection,
-                        request: Request::new(),
+                        socket,
+                        request: Request::new(self.config.clone()),
+                        setup_deadline: self.config.setup_timeout.map(|timeout| now + timeout),
+                        datagrams: VecDeque::new(),
                     },
                 );
 
                 println!("accepted {:?}", connection_handle);
+                self.outbound.recycle(buf);
 
                 Ok(connection_handle)
             }
             Err(error) => {
                 // Failed to accept the connection -- possibly transmit back a response
-                if let Some(transmit) = error.response {
-                    self.outbound.push(transmit, &mut self.buf);
+                match error.response {
+                    Some(transmit) => self.outbound.push(socket, None, transmit, buf),
+                    None => self.outbound.recycle(buf),
                 }
 
                 Err(error.cause)
@@ -195,9 +278,34 @@ impl Server {
         }
     }
 
-    fn prepare_response_buf(&mut self) {
-        self.buf.clear();
-        self.buf
-            .reserve(self.endpoint.config().get_max_udp_payload_size() as usize);
+    /// The time to hand to the endpoint for a received packet.
+    fn packet_now(&self, now: Instant, meta: &RecvMeta) -> Instant {
+        if self.packet_timestamps == false {
+            return now;
+        }
+
+        let Some(timestamp) = meta.timestamp.and_then(util::timestamp_to_instant) else {
+            return now;
+        };
+
+        // Time must never go backwards for the endpoint, or past the `now` we'll process with
+        timestamp.max(self.last_now).min(now)
     }
 }
+
+/// Builds a JSON array of the certificate's SHA-256 hashes, in the form expected by the
+/// `serverCertificateHashes` WebTransport option (but with hex-encoded values).
+fn cert_hashes_json(certs: &[CertificateDer<'static>]) -> String {
+    // Only the end-entity certificate is relevant for serverCertificateHashes
+    let hashes = certs.iter().take(1).map(|cert| {
+        let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+        let value: String = digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{{\"algorithm\":\"sha-256\",\"value\":\"{}\"}}", value)
+    });
+
+    format!("[{}]", hashes.collect::<Vec<_>>().join(","))
+}
@@ -1,39 +1,72 @@
+use std::collections::VecDeque;
 use std::io::Cursor;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bytes::{Bytes, BytesMut};
 use http::StatusCode;
 use quinn_proto::coding::Codec;
-use quinn_proto::{Connection, SendDatagramError, VarInt};
+use quinn_proto::{
+    Connection, ConnectionEvent, ConnectionHandle, SendDatagramError, StreamId, VarInt,
+};
 
 use crate::outbound::Outbound;
-use crate::webtransport::{Request, RequestState, WebTransportError};
+use crate::webtransport::{
+    self, Capabilities, Request, RequestState, SessionEvent, WebTransportError,
+};
 
 /// The maximum of datagrams a Server will produce via `poll_transmit`
-const MAX_DATAGRAMS: usize = 10;
+pub(crate) const MAX_DATAGRAMS: usize = 10;
 
 /// The maximum number of transmit loop iterations for a single connection.
 const MAX_TRANSMIT_OPS: usize = 3;
 
+/// The space offered to `send_datagram` callers when datagrams are sent as capsules instead.
+///
+/// Capsules have no real size limit, so this just matches a typical QUIC datagram.
+const CAPSULE_DATAGRAM_SIZE: usize = 1200;
+
+/// The maximum number of received datagrams held for the application before the oldest are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+
+/// A datagram payload, along with when the UDP packet carrying it arrived.
+#[derive(Debug, Clone)]
+pub struct ReceivedDatagram {
+    pub payload: Bytes,
+    /// The kernel receive timestamp of the packet, as reported by the socket (measured from the
+    /// UNIX epoch). This is `None` for datagrams that arrived as capsules on the CONNECT stream.
+    pub timestamp: Option<Duration>,
+}
+
 pub struct Session {
     pub(crate) inner: Connection,
+    pub(crate) socket: usize, // The socket the connection arrived on, which we always reply from
     pub(crate) request: Request,
+    pub(crate) setup_deadline: Option<Instant>,
+    pub(crate) datagrams: VecDeque<(Bytes, Option<Duration>)>,
 }
 
 impl Session {
-    pub fn recv_datagram(&mut self) -> Result<Option<Bytes>, WebTransportError> {
-        let Some(mut bytes) = self.inner.datagrams().recv() else {
-            return Ok(None);
+    pub fn recv_datagram(&mut self) -> Result<Option<ReceivedDatagram>, WebTransportError> {
+        let Some((mut bytes, timestamp)) = self.datagrams.pop_front() else {
+            // Without QUIC datagrams, the peer may be sending them as capsules instead
+            let payload = self.request.recv_capsule_datagram();
+            return Ok(payload.map(|payload| ReceivedDatagram {
+                payload,
+                timestamp: None,
+            }));
         };
         let Some(completed) = self.request.completed() else {
             Err(WebTransportError::WebTransportNotConnected)?
         };
 
         let mut cursor = Cursor::new(&bytes);
-        if VarInt::decode(&mut cursor)? != completed.session_id {
+        if VarInt::decode(&mut cursor)? != completed.quarter_stream_id {
             return Err(WebTransportError::UnexpectedSessionId);
         }
-        Ok(Some(bytes.split_off(cursor.position() as usize)))
+        Ok(Some(ReceivedDatagram {
+            payload: bytes.split_off(cursor.position() as usize),
+            timestamp,
+        }))
     }
 
     pub fn send_datagram(
@@ -43,8 +76,21 @@ impl Session {
         let Some(completed) = self.request.completed() else {
             Err(WebTransportError::WebTransportNotConnected)?
         };
-        let Some(max_size) = self.inner.datagrams().max_size() else {
-            Err(SendDatagramError::UnsupportedByPeer)?
+        // Peers that didn't agree to HTTP datagrams only ever get them as capsules
+        let h3_datagram = self.request.capabilities().is_some_and(|c| c.h3_datagram);
+        let max_size = match h3_datagram {
+            true => self.inner.datagrams().max_size(),
+            false => None,
+        };
+
+        let Some(max_size) = max_size else {
+            if self.request.capsule_fallback() == false {
+                Err(SendDatagramError::UnsupportedByPeer)?
+            }
+
+            let mut buf = vec![0; CAPSULE_DATAGRAM_SIZE];
+            let written = fill(&mut buf);
+            return self.request.send_capsule_datagram(&buf[..written]);
         };
 
         let bytes = {
@@ -58,14 +104,142 @@ impl Session {
         Ok(self.inner.datagrams().send(bytes, true)?)
     }
 
+    /// What the peer supports, once its SETTINGS have been received and validated.
+    #[allow(dead_code)]
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.request.capabilities()
+    }
+
+    /// Returns the next event from the peer's HTTP/3 control stream, if any.
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.request.poll_event()
+    }
+
+    /// Takes the next unidirectional stream the peer opened for this WebTransport session.
+    ///
+    /// The stream's WebTransport header has already been read and the rest is application data.
+    pub fn accept_uni(&mut self) -> Option<StreamId> {
+        self.request.accept_uni()
+    }
+
+    /// Takes the next bidirectional stream the peer opened for this WebTransport session.
+    ///
+    /// The stream's WebTransport header has already been read and the rest is application data.
+    pub fn accept_bi(&mut self) -> Option<StreamId> {
+        self.request.accept_bi()
+    }
+
+    /// Reads everything that has arrived on one of the session's streams into `buf`.
+    ///
+    /// Returns `true` once the peer has finished the stream. This fails if the peer reset the
+    /// stream, so check `received_reset` first to learn why.
+    pub fn read_stream(
+        &mut self,
+        id: StreamId,
+        buf: &mut Vec<u8>,
+    ) -> Result<bool, WebTransportError> {
+        webtransport::read_to(&mut self.inner, id, buf, usize::MAX)
+    }
+
+    /// Writes as much of `data` as flow control allows, returning the number of bytes written.
+    ///
+    /// This fails if the peer stopped the stream, so check `stopped` first to learn why.
+    pub fn write_stream(&mut self, id: StreamId, data: &[u8]) -> Result<usize, WebTransportError> {
+        webtransport::write_some(&mut self.inner, id, data)
+    }
+
+    /// Finishes one of the session's send streams once everything written to it has been sent.
+    pub fn finish_stream(&mut self, id: StreamId) -> Result<(), WebTransportError> {
+        Ok(self.inner.send_stream(id).finish()?)
+    }
+
+    /// Resets one of the session's send streams with a WebTransport application error code.
+    pub fn reset_stream(&mut self, id: StreamId, code: u32) -> Result<(), WebTransportError> {
+        let code = webtransport::webtransport_to_http3(code);
+        Ok(self.inner.send_stream(id).reset(code)?)
+    }
+
+    /// Asks the peer to stop sending on one of the session's streams, with a WebTransport
+    /// application error code.
+    pub fn stop_stream(&mut self, id: StreamId, code: u32) -> Result<(), WebTransportError> {
+        let code = webtransport::webtransport_to_http3(code);
+        Ok(self.inner.recv_stream(id).stop(code)?)
+    }
+
+    /// The WebTransport application error code the peer reset one of our receive streams with.
+    ///
+    /// Returns `None` if the stream hasn't been reset. Once a code is returned, the stream is gone.
+    pub fn received_reset(&mut self, id: StreamId) -> Result<Option<u32>, WebTransportError> {
+        match self.inner.recv_stream(id).received_reset()? {
+            Some(code) => map_stream_code(code).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The WebTransport application error code the peer stopped one of our send streams with.
+    ///
+    /// Returns `None` if the stream hasn't been stopped.
+    pub fn stopped(&mut self, id: StreamId) -> Result<Option<u32>, WebTransportError> {
+        match self.inner.send_stream(id).stopped()? {
+            Some(code) => map_stream_code(code).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Feeds a packet to the connection, tagging any datagrams it carried with its timestamp.
+    pub(crate) fn handle_event(&mut self, event: ConnectionEvent, timestamp: Option<Duration>) {
+        self.inner.handle_event(event);
+
+        // The connection processes the packet right away, so anything new came from this packet
+        while let Some(bytes) = self.inner.datagrams().recv() {
+            if self.datagrams.len() >= MAX_QUEUED_DATAGRAMS {
+                self.datagrams.pop_front(); // Prefer fresh datagrams, like quinn does
+            }
+
+            self.datagrams.push_back((bytes, timestamp));
+        }
+    }
+
+    /// Closes the connection if WebTransport setup hasn't completed by its deadline.
+    ///
+    /// Returns true if the deadline passed and the connection was closed as a result.
+    pub(crate) fn handle_setup_deadline(&mut self, now: Instant) -> bool {
+        let Some(deadline) = self.setup_deadline else {
+            return false;
+        };
+
+        if self.request.completed().is_some() || self.inner.is_closed() {
+            self.setup_deadline = None;
+            return false;
+        }
+
+        if now < deadline {
+            return false;
+        }
+
+        let error = WebTransportError::SetupTimeout;
+        self.inner
+            .close(now, error.code(), Bytes::from(error.to_string()));
+        self.setup_deadline = None;
+        true
+    }
+
+    /// The earliest of the connection's own timers and the setup deadline.
+    pub(crate) fn poll_timeout(&mut self) -> Option<Instant> {
+        match (self.inner.poll_timeout(), self.setup_deadline) {
+            (Some(timeout), Some(deadline)) => Some(timeout.min(deadline)),
+            (timeout, deadline) => timeout.or(deadline),
+        }
+    }
+
     pub(crate) fn handle_process(
         &mut self,
         now: Instant,
-        buf: &mut Vec<u8>,
+        handle: ConnectionHandle,
         outbound: &mut Outbound,
     ) {
         // Update the webtransport connection request state machine
-        if self.inner.is_drained() == false {
+        if self.inner.is_closed() == false {
             'wt: loop {
                 match self.request.update(&mut self.inner) {
                     Ok(RequestState::ConnectData(url)) => {
@@ -81,7 +255,10 @@ impl Session {
                         break 'wt;
                     }
                     Err(e) => {
-                        println!("got error: {:?}", e);
+                        // Every error here is fatal, so close right away rather than idling out
+                        println!("closing connection: {:?}", e);
+                        let reason = Bytes::from(e.to_string());
+                        self.inner.close(now, e.code(), reason);
                         break 'wt;
                     }
                 }
@@ -91,11 +268,13 @@ impl Session {
         let mut transmit_ops = 0;
 
         loop {
-            if let Some(transmit) = self.inner.poll_transmit(now, MAX_DATAGRAMS, buf) {
-                outbound.push(transmit, buf);
+            let mut buf = outbound.take_buffer();
+            if let Some(transmit) = self.inner.poll_transmit(now, MAX_DATAGRAMS, &mut buf) {
+                outbound.push(self.socket, Some(handle), transmit, buf);
                 transmit_ops += 1;
             } else {
                 // Nothing (left) to transmit, but still check timeouts
+                outbound.recycle(buf);
                 transmit_ops = MAX_TRANSMIT_OPS;
             }
 
@@ -109,6 +288,11 @@ impl Session {
     }
 }
 
+fn map_stream_code(code: VarInt) -> Result<u32, WebTransportError> {
+    webtransport::http3_to_webtransport(code)
+        .ok_or(WebTransportError::UnmappedErrorCode(code.into_inner()))
+}
+
 // TODO
 // pub fn close_recv_stream(recv_stream: &mut RecvStream) {
 //     _ = recv_stream.stop(0u32.into()); // Ignore ClosedStream errors
@@ -121,3 +305,139 @@ impl Session {
 //         Err(FinishError::ClosedStream) => (), // Already closed
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::{Dir, Side};
+
+    use super::*;
+    use crate::webtransport::WebTransportConfig;
+    use crate::webtransport::testing::{self, TestClient};
+
+    #[test]
+    fn datagrams_use_the_quarter_stream_id() {
+        let mut client = TestClient::connect(WebTransportConfig::default());
+        client.open_uni(&testing::client_settings());
+
+        // Take up the first request stream, so the session ID isn't its own quarter
+        let mut data = Vec::new();
+        testing::headers_frame(&[(":method", "GET"), (":path", "/")], &mut data);
+        client.open_bi(&data);
+        let connect = client.send_connect();
+        client.drive();
+
+        let completed = client.session().request.completed().unwrap();
+        assert_eq!(completed.session_id, VarInt::from(connect));
+        assert_eq!(connect, StreamId::new(Side::Client, Dir::Bi, 1));
+
+        let mut datagram = Vec::new();
+        VarInt::from_u32(1).encode(&mut datagram);
+        datagram.extend_from_slice(b"hello");
+        client
+            .connection
+            .datagrams()
+            .send(Bytes::from(datagram), true)
+            .unwrap();
+        client.drive();
+
+        let received = client.session().recv_datagram().unwrap().unwrap();
+        assert_eq!(received.payload, "hello");
+
+        client
+            .session()
+            .send_datagram(|buf| {
+                buf[..2].copy_from_slice(b"hi");
+                2
+            })
+            .unwrap();
+        client.drive();
+
+        let sent = client.connection.datagrams().recv().unwrap();
+        assert_eq!(sent, [1, b'h', b'i'][..]);
+    }
+
+    #[test]
+    fn closes_connections_that_miss_the_setup_deadline() {
+        let mut config = WebTransportConfig::default();
+        config.setup_timeout = Some(Duration::from_secs(1));
+        let mut client = TestClient::connect(config);
+
+        client.advance(Duration::from_millis(500));
+        assert_eq!(client.server.stats().setup_timeouts, 0);
+        assert_eq!(client.close_code(), None);
+
+        client.advance(Duration::from_millis(600));
+        assert_eq!(client.server.stats().setup_timeouts, 1);
+        let code = WebTransportError::SetupTimeout.code();
+        assert_eq!(client.close_code(), Some(code));
+    }
+
+    #[test]
+    fn established_sessions_have_no_setup_deadline() {
+        let mut config = WebTransportConfig::default();
+        config.setup_timeout = Some(Duration::from_secs(1));
+        let mut client = TestClient::establish(config);
+
+        client.advance(Duration::from_secs(2));
+        assert_eq!(client.server.stats().setup_timeouts, 0);
+        assert_eq!(client.close_code(), None);
+    }
+
+    /// Opens a WebTransport bidirectional stream for the client's session, with some data on it.
+    fn open_webtransport_bi(client: &mut TestClient) -> StreamId {
+        let mut data = Vec::new();
+        VarInt::from_u32(0x41).encode(&mut data);
+        VarInt::from_u32(0).encode(&mut data);
+        data.extend_from_slice(b"hello");
+
+        let id = client.open_bi(&data);
+        client.drive();
+        assert_eq!(client.session().accept_bi(), Some(id));
+        id
+    }
+
+    #[test]
+    fn reads_and_writes_streams() {
+        let mut client = TestClient::establish(WebTransportConfig::default());
+        let id = open_webtransport_bi(&mut client);
+        client.connection.send_stream(id).finish().unwrap();
+        client.drive();
+
+        let mut buf = Vec::new();
+        assert!(client.session().read_stream(id, &mut buf).unwrap());
+        assert_eq!(buf, b"hello");
+
+        assert_eq!(client.session().write_stream(id, b"world").unwrap(), 5);
+        client.session().finish_stream(id).unwrap();
+        client.drive();
+        assert_eq!(client.read(id), b"world");
+    }
+
+    #[test]
+    fn maps_stream_error_codes() {
+        let mut client = TestClient::establish(WebTransportConfig::default());
+        let id = open_webtransport_bi(&mut client);
+
+        let code = webtransport::webtransport_to_http3(42);
+        client.connection.send_stream(id).reset(code).unwrap();
+        client.connection.recv_stream(id).stop(code).unwrap();
+        client.drive();
+
+        assert_eq!(client.session().received_reset(id).unwrap(), Some(42));
+        assert_eq!(client.session().stopped(id).unwrap(), Some(42));
+        assert!(client.session().write_stream(id, b"world").is_err());
+
+        // Codes from outside the WebTransport range aren't application codes
+        let id = open_webtransport_bi(&mut client);
+        client
+            .connection
+            .send_stream(id)
+            .reset(VarInt::from_u32(7))
+            .unwrap();
+        client.drive();
+        assert!(matches!(
+            client.session().received_reset(id),
+            Err(WebTransportError::UnmappedErrorCode(7))
+        ));
+    }
+}
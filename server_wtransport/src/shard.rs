@@ -0,0 +1,303 @@
+use std::io::Result as IoResult;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use mio::{Registry, Token, Waker};
+use quinn_proto::{ConnectionId, ConnectionIdGenerator, InvalidCid};
+use quinn_udp::RecvMeta;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// The length of the connection IDs we issue when sharded (the same as quinn's default).
+const CID_LEN: usize = 8;
+
+/// The most forwarded packets a shard will have waiting, past which new ones are dropped.
+const MAX_QUEUED_PACKETS: usize = 1024;
+
+/// The only QUIC version whose long header packet types we know how to read.
+const QUIC_V1: [u8; 4] = [0, 0, 0, 1];
+
+/// One of several servers sharing a port, each running on its own thread.
+///
+/// The kernel spreads packets across the shards' SO_REUSEPORT sockets by address, so a client
+/// whose address changes (after a NAT rebinding, say) can land on the wrong shard. To find the
+/// right one again, every connection ID a shard issues encodes its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+/// A packet forwarded from another shard, along with the index of the socket it arrived on.
+pub struct ForwardedPacket {
+    pub socket: usize,
+    pub data: BytesMut,
+    pub meta: RecvMeta,
+}
+
+/// Passes packets between shards, waking up the receiving shard's event loop.
+///
+/// Each shard's queue is bounded, so a shard that falls behind can't pile up memory. Packets that
+/// don't fit are dropped, and QUIC recovers them like any other loss.
+pub struct ShardLink {
+    shard: Shard,
+    receiver: Receiver<ForwardedPacket>,
+    peers: Vec<(SyncSender<ForwardedPacket>, Arc<Waker>)>, // Indexed by shard
+    dropped: u64,
+}
+
+/// Issues connection IDs that start with a byte identifying the shard.
+///
+/// That byte is the shard index plus a random multiple of the shard count, so it doesn't give
+/// the index away outright. The rest of the ID is random.
+struct ShardCidGenerator {
+    shard: Shard,
+    rng: SystemRandom,
+}
+
+impl Shard {
+    pub fn new(index: usize, count: usize) -> Self {
+        assert!(count > 0 && count <= 256, "shard count must fit in a byte");
+        assert!(index < count, "shard index out of range");
+        Self { index, count }
+    }
+
+    /// The shard that owns a packet's connection, going by the connection ID we issued for it.
+    ///
+    /// Returns `None` for packets addressed with an ID the client picked (Initial and 0-RTT), or
+    /// that we can't parse. Those stay with the shard the kernel gave them to.
+    pub fn owner(&self, packet: &[u8]) -> Option<usize> {
+        let first = *packet.first()?;
+
+        let cid = if first & 0x80 == 0 {
+            // Short header, where the ID directly follows the first byte
+            packet.get(1..1 + CID_LEN)?
+        } else {
+            // Long header, where only Handshake packets (type 0x2 in QUIC v1) carry one of our IDs.
+            // Other versions number their packet types differently, so we leave those alone.
+            let version = packet.get(1..5)?;
+            if version != QUIC_V1 || (first >> 4) & 0b11 != 0x2 {
+                return None;
+            }
+
+            let len = *packet.get(5)? as usize;
+            if len != CID_LEN {
+                return None;
+            }
+
+            packet.get(6..6 + len)?
+        };
+
+        Some(cid[0] as usize % self.count)
+    }
+
+    /// A factory for the endpoint's connection ID generators, which encode this shard's index.
+    pub fn cid_generator(self) -> impl Fn() -> Box<dyn ConnectionIdGenerator> + Send + Sync {
+        move || {
+            Box::new(ShardCidGenerator {
+                shard: self,
+                rng: SystemRandom::new(),
+            })
+        }
+    }
+}
+
+impl ShardLink {
+    /// Links every shard to every other, registering a waker under `token` with each registry.
+    ///
+    /// Returns one link per registry, in the same order.
+    pub fn link_all(registries: &[&Registry], token: Token) -> IoResult<Vec<ShardLink>> {
+        let count = registries.len();
+
+        let mut receivers = Vec::with_capacity(count);
+        let mut peers = Vec::with_capacity(count);
+        for registry in registries {
+            let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_PACKETS);
+            receivers.push(receiver);
+            peers.push((sender, Arc::new(Waker::new(registry, token)?)));
+        }
+
+        let links = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, receiver)| ShardLink {
+                shard: Shard::new(index, count),
+                receiver,
+                peers: peers.clone(),
+                dropped: 0,
+            })
+            .collect();
+
+        Ok(links)
+    }
+
+    pub fn shard(&self) -> Shard {
+        self.shard
+    }
+
+    /// The number of packets we couldn't forward because the owning shard's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Sends a packet on to the shard that owns its connection, unless that's this one.
+    ///
+    /// Returns the packet's data back if it should be handled here.
+    pub fn forward(&mut self, socket: usize, data: BytesMut, meta: &RecvMeta) -> Option<BytesMut> {
+        let owner = match self.shard.owner(&data) {
+            Some(owner) if owner != self.shard.index => owner,
+            _ => return Some(data),
+        };
+
+        let (sender, waker) = &self.peers[owner];
+        let packet = ForwardedPacket {
+            socket,
+            data,
+            meta: *meta,
+        };
+
+        match sender.try_send(packet) {
+            Ok(()) => _ = waker.wake(), // If this fails the shard is gone, and so is the packet
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            Err(TrySendError::Disconnected(_)) => {} // The shard is gone
+        }
+
+        None
+    }
+
+    /// Takes the next packet another shard forwarded to us, if any.
+    pub fn recv(&self) -> Option<ForwardedPacket> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl ConnectionIdGenerator for ShardCidGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut bytes = [0; CID_LEN];
+        self.rng
+            .fill(&mut bytes)
+            .expect("failed to generate connection ID");
+
+        // The largest multiple of the count we can add while still fitting in a byte
+        let max_multiple = (255 - self.shard.index) / self.shard.count;
+        let multiple = bytes[0] as usize % (max_multiple + 1);
+        bytes[0] = (multiple * self.shard.count + self.shard.index) as u8;
+
+        ConnectionId::new(&bytes)
+    }
+
+    fn validate(&self, cid: &ConnectionId) -> Result<(), InvalidCid> {
+        match cid.first() {
+            Some(&first) if first as usize % self.shard.count == self.shard.index => Ok(()),
+            _ => Err(InvalidCid),
+        }
+    }
+
+    fn cid_len(&self) -> usize {
+        CID_LEN
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::Poll;
+
+    use super::*;
+
+    fn short_header(cid: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x40];
+        packet.extend_from_slice(cid);
+        packet.extend_from_slice(&[0; 32]);
+        packet
+    }
+
+    fn long_header(ty: u8, version: [u8; 4], cid: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xc0 | (ty << 4)];
+        packet.extend_from_slice(&version);
+        packet.push(cid.len() as u8);
+        packet.extend_from_slice(cid);
+        packet.extend_from_slice(&[0; 32]);
+        packet
+    }
+
+    #[test]
+    fn owner_reads_our_connection_ids() {
+        let shard = Shard::new(0, 4);
+        let cid = [6, 1, 2, 3, 4, 5, 6, 7];
+
+        assert_eq!(shard.owner(&short_header(&cid)), Some(2));
+        assert_eq!(shard.owner(&long_header(0x2, QUIC_V1, &cid)), Some(2));
+    }
+
+    #[test]
+    fn owner_ignores_packets_it_cant_attribute() {
+        let shard = Shard::new(0, 4);
+        let cid = [6, 1, 2, 3, 4, 5, 6, 7];
+        let v2 = [0x6b, 0x33, 0x43, 0xcf];
+
+        // Initial and 0-RTT packets carry an ID the client picked
+        assert_eq!(shard.owner(&long_header(0x0, QUIC_V1, &cid)), None);
+        assert_eq!(shard.owner(&long_header(0x1, QUIC_V1, &cid)), None);
+        // Version negotiation, and versions whose packet types we don't know
+        assert_eq!(shard.owner(&long_header(0x2, [0; 4], &cid)), None);
+        assert_eq!(shard.owner(&long_header(0x2, v2, &cid)), None);
+        // IDs we didn't issue, and truncated packets
+        assert_eq!(shard.owner(&long_header(0x2, QUIC_V1, &cid[..4])), None);
+        assert_eq!(shard.owner(&[0x40, 1, 2]), None);
+        assert_eq!(shard.owner(&[]), None);
+    }
+
+    #[test]
+    fn generated_ids_belong_to_their_shard() {
+        for count in [1, 2, 3, 7, 256] {
+            for index in [0, count / 2, count - 1] {
+                let shard = Shard::new(index, count);
+                let mut generator = (shard.cid_generator())();
+
+                for _ in 0..100 {
+                    let cid = generator.generate_cid();
+                    assert_eq!(cid.len(), CID_LEN);
+                    assert!(generator.validate(&cid).is_ok());
+                    assert_eq!(shard.owner(&short_header(&cid)), Some(index));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn forwarding_drops_packets_past_the_queue_limit() {
+        let polls = [Poll::new().unwrap(), Poll::new().unwrap()];
+        let registries: Vec<&Registry> = polls.iter().map(Poll::registry).collect();
+        let mut links = ShardLink::link_all(&registries, Token(0)).unwrap();
+
+        // An ID owned by the second shard, received by the first
+        let packet = short_header(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        let meta = RecvMeta::default();
+        for _ in 0..MAX_QUEUED_PACKETS + 5 {
+            let data = BytesMut::from(&packet[..]);
+            assert!(links[0].forward(3, data, &meta).is_none());
+        }
+        assert_eq!(links[0].dropped(), 5);
+
+        let mut received = 0;
+        while let Some(forwarded) = links[1].recv() {
+            assert_eq!(forwarded.socket, 3);
+            assert_eq!(&forwarded.data[..], &packet[..]);
+            received += 1;
+        }
+        assert_eq!(received, MAX_QUEUED_PACKETS);
+
+        // Packets the first shard owns stay with it
+        let packet = short_header(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(
+            links[0]
+                .forward(0, BytesMut::from(&packet[..]), &meta)
+                .is_some()
+        );
+    }
+}
@@ -1,13 +1,21 @@
-use std::io::{ErrorKind, IoSliceMut, Result as IoResult};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, IoSliceMut, Result as IoResult};
+use std::mem;
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 use mio::event::Source;
 use mio::net::UdpSocket;
 use mio::{Interest, Registry, Token};
+use quinn_proto::ConnectionHandle;
 use quinn_proto::Transmit as ProtoTransmit;
-use quinn_udp::{RecvMeta, Transmit as UdpTransmit, UdpSocketState};
+#[cfg(not(target_os = "linux"))]
+use quinn_udp::Transmit as UdpTransmit;
+use quinn_udp::{RecvMeta, UdpSocketState};
+use socket2::{Domain, Protocol, SockRef, Type};
 
+use crate::outbound::{Outbound, Queued};
 use crate::util;
 
 #[cfg(target_os = "linux")]
@@ -15,18 +23,132 @@ const BATCH_COUNT: usize = 32;
 #[cfg(target_os = "windows")]
 const BATCH_COUNT: usize = 1;
 
-pub(crate) struct Socket<'a> {
+/// The most transmits we'll hold on to while waiting for the socket to become writable.
+const MAX_PENDING_SENDS: usize = 1024;
+
+/// Options applied to the UDP socket when it's created.
+///
+/// Anything left as `None` keeps the kernel's default.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SocketConfig {
+    /// The receive buffer size (SO_RCVBUF) to ask for, in bytes.
+    pub recv_buffer_size: Option<usize>,
+    /// The send buffer size (SO_SNDBUF) to ask for, in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// The DSCP codepoint to mark outgoing packets with (the upper six bits of the IPv4 TOS or
+    /// IPv6 traffic class byte, below which we keep setting ECN per packet).
+    ///
+    /// This is best-effort. On Linux every packet carries it, but elsewhere it's only set on the
+    /// socket, and the ECN codepoint quinn-udp sets per packet may replace it. Check
+    /// `SocketOptions::dscp_per_packet` to see which applies.
+    pub dscp: Option<u8>,
+    /// How long to busy poll the device queue on blocking reads (SO_BUSY_POLL), in microseconds.
+    ///
+    /// Only supported on Linux, where values above `net.core.busy_poll` need CAP_NET_ADMIN.
+    pub busy_poll: Option<u32>,
+    /// Whether an IPv6 socket handles IPv6 traffic only (IPV6_V6ONLY).
+    ///
+    /// Turn this off when binding `[::]` to accept IPv4 clients too, which then show up under
+    /// IPv4-mapped addresses. Ignored for IPv4 sockets.
+    pub only_v6: Option<bool>,
+    /// Whether to let other sockets bind the same address (SO_REUSEPORT), which the kernel then
+    /// spreads incoming packets across. Only supported on Unix.
+    pub reuse_port: bool,
+}
+
+/// The socket options the kernel actually granted, which may differ from what was asked for.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SocketOptions {
+    /// The receive buffer size. Linux reports double the requested size (for its own
+    /// bookkeeping), clamped to `net.core.rmem_max` unless the caller has CAP_NET_ADMIN.
+    pub recv_buffer_size: usize,
+    /// The send buffer size, reported the same way as `recv_buffer_size`.
+    pub send_buffer_size: usize,
+    /// The DSCP codepoint set on the socket.
+    pub dscp: u8,
+    /// Whether we mark every packet with `dscp` ourselves. If not, marking is up to the platform,
+    /// which may drop it whenever it sets the packet's ECN codepoint.
+    pub dscp_per_packet: bool,
+    /// The busy poll time, if the platform supports it.
+    pub busy_poll: Option<u32>,
+    /// Whether the socket is IPv6-only, or `None` for IPv4 sockets.
+    pub only_v6: Option<bool>,
+}
+
+/// Counters for sends that couldn't go out right away, for monitoring.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SendStats {
+    /// Transmits that were queued because the socket's send buffer was full.
+    pub deferred: u64,
+    /// Queued transmits that were dropped because too many were already waiting.
+    pub dropped: u64,
+}
+
+/// When the kernel actually sent one of our datagrams.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TxTimestamp {
+    /// The id of the send this timestamp belongs to, out of the `TxIds` it was given.
+    pub id: u32,
+    /// The software transmit timestamp, measured from the UNIX epoch like receive timestamps.
+    pub timestamp: Duration,
+}
+
+/// The TX timestamp ids given to a transmit, one per send syscall it took.
+///
+/// A GSO batch sent in one go takes a single id, while one split into segments takes one each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TxIds {
+    pub first: u32,
+    pub count: u32,
+}
+
+/// A transmit that went out while TX timestamping was on, for matching up with its timestamps.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SentTransmit {
+    /// The connection that sent it, or `None` if it came from the endpoint itself.
+    pub connection: Option<ConnectionHandle>,
+    pub destination: SocketAddr,
+    pub size: usize,
+    pub tx_ids: TxIds,
+}
+
+pub(crate) struct Socket {
     sock_mio: UdpSocket,
     sock_quic: UdpSocketState,
 
-    iovs: [IoSliceMut<'a>; BATCH_COUNT],
+    recv_buf: Box<[u8]>, // Split into BATCH_COUNT chunks of recv_chunk_size for each recv call
+    recv_chunk_size: usize,
     metas: [RecvMeta; BATCH_COUNT],
+
+    tx_next_id: Option<u32>, // Only set while TX timestamping is enabled
+    dscp: u8,
+    is_ipv6: bool,  // Whether IPv4 destinations need mapping, as on a dual-stack socket
+    gso: bool,      // Cleared once the kernel says it can't do GSO on this socket
+    gso_sent: bool, // Set once a GSO send has gone through
+
+    send_buf: Vec<Queued>,
+    #[cfg(target_os = "linux")]
+    send_batch: mmsg::Batch,
+
+    pending: VecDeque<Queued>, // Waiting for the socket to become writable
+    sent: Vec<SentTransmit>,   // Only filled while TX timestamping is enabled
+    registered: Option<(Token, Interest)>,
+    stats: SendStats,
+    // Loopback sends never block, so tests use this to act as if the socket's buffer were full
+    #[cfg(test)]
+    full: bool,
 }
 
-impl Socket<'_> {
-    pub fn new(addr: SocketAddr, max_udp_payload_size: usize) -> Self {
-        let sock_mio = UdpSocket::bind(addr).unwrap();
-        let sock_quic = UdpSocketState::new((&sock_mio).into()).unwrap();
+impl Socket {
+    pub fn new(
+        addr: SocketAddr,
+        max_udp_payload_size: usize,
+        config: &SocketConfig,
+    ) -> IoResult<Self> {
+        let sock_mio = bind(addr, config)?;
+        let sock_quic = UdpSocketState::new((&sock_mio).into())?;
+        apply_config(&sock_mio, addr.is_ipv6(), config)?;
 
         let sock_ref = (&sock_mio).into();
         #[cfg(target_os = "windows")]
@@ -34,36 +156,119 @@ impl Socket<'_> {
         #[cfg(target_os = "linux")]
         sock_quic.set_recv_timestamping(sock_ref, true).unwrap();
 
-        let chunk_size = sock_quic.gro_segments() * max_udp_payload_size.min(u16::MAX.into());
-        let total_bytes = chunk_size * BATCH_COUNT;
-
-        let buf = Box::leak(vec![0; total_bytes].into_boxed_slice());
-        let mut chunks = buf.chunks_mut(chunk_size).map(IoSliceMut::new);
-
-        let iovs = std::array::from_fn(|_| chunks.next().unwrap());
+        let recv_chunk_size = sock_quic.gro_segments() * max_udp_payload_size.min(u16::MAX.into());
+        let recv_buf = vec![0; recv_chunk_size * BATCH_COUNT].into_boxed_slice();
         let metas = [RecvMeta::default(); BATCH_COUNT];
 
-        Self {
+        Ok(Self {
             sock_mio,
             sock_quic,
-            iovs,
+            recv_buf,
+            recv_chunk_size,
             metas,
+            tx_next_id: None,
+            dscp: config.dscp.unwrap_or(0),
+            is_ipv6: addr.is_ipv6(),
+            gso: true,
+            gso_sent: false,
+            send_buf: Vec::new(),
+            #[cfg(target_os = "linux")]
+            send_batch: mmsg::Batch::new(),
+            pending: VecDeque::new(),
+            sent: Vec::new(),
+            registered: None,
+            stats: SendStats::default(),
+            #[cfg(test)]
+            full: false,
+        })
+    }
+
+    /// Reads back the socket options the kernel is actually using.
+    pub fn options(&self) -> IoResult<SocketOptions> {
+        let sock_ref = SockRef::from(&self.sock_mio);
+        let tos = match self.is_ipv6 {
+            #[cfg(unix)]
+            true => sock_ref.tclass_v6()?,
+            #[cfg(not(unix))]
+            true => 0, // Windows has no way to read the traffic class back
+            false => sock_ref.tos()?,
+        };
+
+        #[cfg(target_os = "linux")]
+        let busy_poll = Some(sockopt::get(&self.sock_mio, libc::SO_BUSY_POLL)? as u32);
+        #[cfg(not(target_os = "linux"))]
+        let busy_poll = None;
+
+        Ok(SocketOptions {
+            recv_buffer_size: sock_ref.recv_buffer_size()?,
+            send_buffer_size: sock_ref.send_buffer_size()?,
+            dscp: (tos >> 2) as u8,
+            dscp_per_packet: cfg!(target_os = "linux"),
+            busy_poll,
+            only_v6: self.is_ipv6.then(|| sock_ref.only_v6()).transpose()?,
+        })
+    }
+
+    /// Enables software TX timestamps, which are reported through `recv_tx_timestamps`.
+    ///
+    /// Each successful send is numbered from zero, and the kernel reports its timestamp under the
+    /// same number. Re-enabling restarts the numbering.
+    pub fn set_tx_timestamping(&mut self, enabled: bool) -> IoResult<()> {
+        #[cfg(target_os = "linux")]
+        {
+            tx_time::enable(&self.sock_mio, enabled)?;
+            self.tx_next_id = enabled.then_some(0);
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = enabled;
+            Err(ErrorKind::Unsupported.into())
+        }
+    }
+
+    /// Reads every pending TX timestamp from the socket's error queue.
+    pub fn recv_tx_timestamps(&mut self, mut f: impl FnMut(TxTimestamp)) -> IoResult<()> {
+        if self.tx_next_id.is_none() {
+            return Ok(());
+        }
+
+        #[cfg(target_os = "linux")]
+        loop {
+            match tx_time::recv(&self.sock_mio) {
+                Ok(Some(timestamp)) => f(timestamp),
+                Ok(None) => {} // Some other error queue message, which we don't care about
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = &mut f;
+            Ok(())
         }
     }
 
     pub fn recv_all(&mut self, mut f: impl FnMut(BytesMut, &RecvMeta)) -> IoResult<()> {
+        // The slices only borrow our buffer, so building them is cheap and nothing is leaked
+        let mut chunks = self.recv_buf.chunks_mut(self.recv_chunk_size);
+        let mut iovs: [IoSliceMut; BATCH_COUNT] =
+            std::array::from_fn(|_| IoSliceMut::new(chunks.next().unwrap()));
+
         loop {
             let recv = || {
                 self.sock_quic.recv(
                     (&self.sock_mio).into(),
-                    &mut self.iovs, //.
+                    &mut iovs, //.
                     &mut self.metas,
                 )
             };
 
             match self.sock_mio.try_io(recv) {
                 Ok(count) => {
-                    for (meta, buf) in self.metas.iter_mut().zip(self.iovs.iter()).take(count) {
+                    for (meta, buf) in self.metas.iter_mut().zip(iovs.iter()).take(count) {
                         // Windows doesn't have packet timestamping, so fake it
                         #[cfg(target_os = "windows")]
                         time::stamp_meta(meta);
@@ -84,30 +289,296 @@ impl Socket<'_> {
         }
     }
 
-    pub fn try_send(&mut self, transmit: ProtoTransmit, buffer: Bytes) -> IoResult<()> {
+    /// Sends a transmit, returning its TX timestamp ids if TX timestamping is enabled.
+    ///
+    /// GSO batches go out in a single `sendmsg` where possible. If the platform can't do GSO (or
+    /// the kernel says it can't, see [`gso_unsupported`]), the batch is sent one segment at a
+    /// time, with an id for each. Any other error is returned, and GSO stays on. Once any segment
+    /// is out, the transmit counts as sent, even if the socket fills up partway.
+    pub fn try_send(&mut self, transmit: &ProtoTransmit, buffer: &[u8]) -> IoResult<Option<TxIds>> {
+        let segment_size = transmit
+            .segment_size
+            .filter(|&segment_size| segment_size < buffer.len());
+
+        let Some(segment_size) = segment_size else {
+            let id = self.send_one(transmit, buffer)?;
+            return Ok(id.map(|first| TxIds { first, count: 1 }));
+        };
+
+        if self.can_gso() {
+            match self.send_one(transmit, buffer) {
+                Ok(id) => {
+                    self.gso_sent = true;
+                    return Ok(id.map(|first| TxIds { first, count: 1 }));
+                }
+                // The kernel or the driver can't do GSO here, so stop trying and split instead
+                Err(e) if gso_unsupported(&e, self.gso_sent) => self.gso = false,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut tx_ids: Option<TxIds> = None;
+        for (index, segment) in buffer.chunks(segment_size).enumerate() {
+            let id = match self.send_one(transmit, segment) {
+                Ok(id) => id,
+                // Queueing the whole batch again would send the segments already out twice, so
+                // the rest are dropped instead, and QUIC recovers them like any other loss
+                Err(e) if e.kind() == ErrorKind::WouldBlock && index > 0 => break,
+                Err(e) => return Err(e),
+            };
+
+            match (&mut tx_ids, id) {
+                (Some(tx_ids), Some(_)) => tx_ids.count += 1,
+                (None, Some(first)) => tx_ids = Some(TxIds { first, count: 1 }),
+                _ => {}
+            }
+        }
+
+        Ok(tx_ids)
+    }
+
+    /// Sends every queued transmit, batching as many as possible into each syscall.
+    ///
+    /// Transmits that fail for any reason but `WouldBlock` are skipped and passed to `on_error`.
+    /// If the socket's buffer fills up, the rest are queued (up to a limit, past which the oldest
+    /// are dropped) and we ask `registry` to tell us when the socket is writable again. Buffers
+    /// go back to the `outbound` pool as soon as we're done with them.
+    ///
+    /// Only the transmits `outbound` queued under `index` are sent, which should be the index the
+    /// server was given for this socket.
+    ///
+    /// While TX timestamping is enabled, returns every transmit this call sent along with the ids
+    /// its timestamps will be reported under.
+    pub fn send_all(
+        &mut self,
+        registry: &Registry,
+        outbound: &mut Outbound,
+        index: usize,
+        mut on_error: impl FnMut(Error),
+    ) -> IoResult<&[SentTransmit]> {
+        self.sent.clear();
+
+        // Anything already waiting has to go out first, to keep packets in order
+        if self.pending.is_empty() == false {
+            let mut pending = mem::take(&mut self.pending);
+            let sent = self.send_batches(pending.make_contiguous(), &mut on_error);
+            for (_, buffer, _) in pending.drain(..sent) {
+                outbound.recycle(buffer);
+            }
+            self.pending = pending;
+        }
+
+        let mut send_buf = mem::take(&mut self.send_buf);
+        send_buf.extend(outbound.drain(index));
+
+        if self.pending.is_empty() {
+            let sent = self.send_batches(&send_buf, &mut on_error);
+            for (_, buffer, _) in send_buf.drain(..sent) {
+                outbound.recycle(buffer);
+            }
+        }
+
+        // Whatever's left has to wait until the socket is writable again
+        self.stats.deferred += send_buf.len() as u64;
+        self.pending.extend(send_buf.drain(..));
+        self.send_buf = send_buf; // Keep the allocation around for next time
+
+        let overflow = self.pending.len().saturating_sub(MAX_PENDING_SENDS);
+        if overflow > 0 {
+            // The newest packets are the most useful, and QUIC will recover the rest
+            for (_, buffer, _) in self.pending.drain(..overflow) {
+                outbound.recycle(buffer);
+            }
+            self.stats.dropped += overflow as u64;
+        }
+
+        self.set_writable_interest(registry, self.pending.is_empty() == false)?;
+        Ok(&self.sent)
+    }
+
+    /// Sends whatever was queued up while the socket wasn't writable.
+    pub fn flush(
+        &mut self,
+        registry: &Registry,
+        outbound: &mut Outbound,
+        index: usize,
+        on_error: impl FnMut(Error),
+    ) -> IoResult<&[SentTransmit]> {
+        self.send_all(registry, outbound, index, on_error)
+    }
+
+    #[allow(dead_code)]
+    pub fn send_stats(&self) -> SendStats {
+        self.stats
+    }
+
+    /// The number of transmits waiting for the socket to become writable.
+    #[allow(dead_code)]
+    pub fn pending_sends(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Sends as many transmits as possible in order, returning how many were sent (or skipped).
+    ///
+    /// Stops at the first transmit that would block.
+    fn send_batches(&mut self, transmits: &[Queued], on_error: &mut impl FnMut(Error)) -> usize {
+        let mut sent = 0;
+
+        while sent < transmits.len() {
+            #[cfg(test)]
+            if self.full {
+                return sent;
+            }
+
+            let remaining = &transmits[sent..];
+
+            // GSO batches we'd have to split can't go through sendmmsg, so they end the batch
+            let can_batch = |(transmit, buffer, _): &Queued| match transmit.segment_size {
+                Some(segment_size) if segment_size < buffer.len() => self.can_gso(),
+                _ => true,
+            };
+            let batch_len = remaining
+                .iter()
+                .take(BATCH_COUNT)
+                .take_while(|entry| can_batch(entry))
+                .count();
+
+            if batch_len > 1 {
+                #[cfg(target_os = "linux")]
+                match self.sock_mio.try_io(|| {
+                    let messages = remaining[..batch_len]
+                        .iter()
+                        .map(|(transmit, buffer, _)| (transmit, &buffer[..]));
+                    mmsg::send(
+                        &mut self.send_batch,
+                        &self.sock_mio,
+                        messages,
+                        self.dscp,
+                        self.is_ipv6,
+                    )
+                }) {
+                    Ok(count) => {
+                        for (transmit, buffer, connection) in &remaining[..count] {
+                            // Each message is its own send as far as the kernel's ids go
+                            let tx_ids = self.next_tx_id().map(|first| TxIds { first, count: 1 });
+                            self.record_sent(*connection, transmit, buffer, tx_ids);
+                        }
+
+                        sent += count;
+                        continue;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return sent,
+                    Err(_) => {} // Retry the failing transmit on its own, below
+                }
+            }
+
+            // Either a lone transmit, or one that sendmmsg refused, which try_send can fall back on
+            let (transmit, buffer, connection) = &remaining[0];
+            match self.try_send(transmit, buffer) {
+                Ok(tx_ids) => self.record_sent(*connection, transmit, buffer, tx_ids),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return sent,
+                Err(e) => on_error(e),
+            }
+
+            sent += 1;
+        }
+
+        sent
+    }
+
+    fn record_sent(
+        &mut self,
+        connection: Option<ConnectionHandle>,
+        transmit: &ProtoTransmit,
+        buffer: &[u8],
+        tx_ids: Option<TxIds>,
+    ) {
+        if let Some(tx_ids) = tx_ids {
+            self.sent.push(SentTransmit {
+                connection,
+                destination: transmit.destination,
+                size: buffer.len(),
+                tx_ids,
+            });
+        }
+    }
+
+    fn set_writable_interest(&mut self, registry: &Registry, writable: bool) -> IoResult<()> {
+        let Some((token, interests)) = self.registered else {
+            return Ok(()); // Nobody is listening for events anyway
+        };
+
+        let wanted = match writable {
+            true => interests.add(Interest::WRITABLE),
+            false => interests.remove(Interest::WRITABLE).unwrap_or(interests),
+        };
+
+        if wanted != interests {
+            self.sock_mio.reregister(registry, token, wanted)?;
+            self.registered = Some((token, wanted));
+        }
+
+        Ok(())
+    }
+
+    fn can_gso(&self) -> bool {
+        self.gso && self.sock_quic.max_gso_segments() > 1
+    }
+
+    /// Sends `contents` (all of a transmit, or one of its segments) with a single syscall.
+    ///
+    /// Contents longer than the transmit's segment size go out as a GSO batch.
+    fn send_one(&mut self, transmit: &ProtoTransmit, contents: &[u8]) -> IoResult<Option<u32>> {
+        // On Linux this goes through the same path as batches, so every packet carries our DSCP
+        #[cfg(target_os = "linux")]
+        self.sock_mio.try_io(|| {
+            mmsg::send(
+                &mut self.send_batch,
+                &self.sock_mio,
+                std::iter::once((transmit, contents)),
+                self.dscp,
+                self.is_ipv6,
+            )
+        })?;
+
+        #[cfg(not(target_os = "linux"))]
         self.sock_mio.try_io(|| {
             self.sock_quic.try_send(
                 (&self.sock_mio).into(),
                 &UdpTransmit {
-                    destination: transmit.destination,
+                    destination: socket_destination(self.is_ipv6, transmit.destination),
                     ecn: transmit.ecn.map(util::udp_ecn),
-                    contents: &buffer,
-                    segment_size: transmit.segment_size,
+                    contents,
+                    segment_size: segment_size(transmit, contents),
                     src_ip: transmit.src_ip,
                 },
             )
-        })
+        })?;
+
+        Ok(self.next_tx_id())
+    }
+
+    /// The kernel numbers every send once timestamping is on, so we have to as well.
+    fn next_tx_id(&mut self) -> Option<u32> {
+        let id = self.tx_next_id;
+        if let Some(next) = self.tx_next_id.as_mut() {
+            *next = next.wrapping_add(1);
+        }
+
+        id
     }
 }
 
-impl Source for Socket<'_> {
+impl Source for Socket {
     fn register(
         &mut self, //.
         registry: &Registry,
         token: Token,
         interests: Interest,
     ) -> IoResult<()> {
-        self.sock_mio.register(registry, token, interests)
+        self.sock_mio.register(registry, token, interests)?;
+        self.registered = Some((token, interests));
+        Ok(())
     }
 
     fn reregister(
@@ -116,26 +587,949 @@ impl Source for Socket<'_> {
         token: Token,
         interests: Interest,
     ) -> IoResult<()> {
-        self.sock_mio.reregister(registry, token, interests)
+        self.sock_mio.reregister(registry, token, interests)?;
+        self.registered = Some((token, interests));
+        Ok(())
     }
 
     fn deregister(&mut self, registry: &Registry) -> IoResult<()> {
-        self.sock_mio.deregister(registry)
+        self.sock_mio.deregister(registry)?;
+        self.registered = None;
+        Ok(())
+    }
+}
+
+/// Where to address a transmit from a socket. A dual-stack socket only takes IPv6 addresses, so
+/// IPv4 destinations go in their IPv4-mapped form.
+fn socket_destination(is_ipv6: bool, destination: SocketAddr) -> SocketAddr {
+    match (is_ipv6, destination) {
+        (true, SocketAddr::V4(addr)) => (addr.ip().to_ipv6_mapped(), addr.port()).into(),
+        (_, destination) => destination,
+    }
+}
+
+/// Whether a failed GSO send means the socket can't do GSO, as opposed to an error that any
+/// send could have hit. Like quinn-udp, that's EIO (the driver has no checksum offload) or, as
+/// long as no GSO send has worked yet, EINVAL (the kernel doesn't support `UDP_SEGMENT`).
+fn gso_unsupported(error: &Error, gso_sent: bool) -> bool {
+    match error.raw_os_error() {
+        Some(libc::EIO) => true,
+        Some(libc::EINVAL) => gso_sent == false,
+        _ => false,
+    }
+}
+
+/// The GSO segment size to send `contents` with, or `None` if it's a single datagram.
+fn segment_size(transmit: &ProtoTransmit, contents: &[u8]) -> Option<usize> {
+    transmit
+        .segment_size
+        .filter(|&segment_size| segment_size < contents.len())
+}
+
+/// Binds a non-blocking UDP socket, applying the options that have to be set beforehand.
+fn bind(addr: SocketAddr, config: &SocketConfig) -> IoResult<UdpSocket> {
+    let socket = socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let (SocketAddr::V6(_), Some(only_v6)) = (addr, config.only_v6) {
+        socket.set_only_v6(only_v6)?;
+    }
+
+    if config.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(ErrorKind::Unsupported.into());
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+fn apply_config(socket: &UdpSocket, is_ipv6: bool, config: &SocketConfig) -> IoResult<()> {
+    let sock_ref = SockRef::from(socket);
+
+    if let Some(size) = config.recv_buffer_size {
+        sock_ref.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        sock_ref.set_send_buffer_size(size)?;
+    }
+
+    if let Some(dscp) = config.dscp {
+        if dscp > 0b11_1111 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "DSCP must fit in six bits",
+            ));
+        }
+
+        let tos = (dscp as u32) << 2;
+        match is_ipv6 {
+            #[cfg(unix)]
+            true => {
+                sock_ref.set_tclass_v6(tos)?;
+                // IPv4-mapped traffic on a dual-stack socket uses the IPv4 option instead
+                _ = sock_ref.set_tos(tos);
+            }
+            #[cfg(not(unix))]
+            true => return Err(ErrorKind::Unsupported.into()),
+            false => sock_ref.set_tos(tos)?,
+        }
+    }
+
+    if let Some(busy_poll) = config.busy_poll {
+        #[cfg(target_os = "linux")]
+        sockopt::set(socket, libc::SO_BUSY_POLL, busy_poll as libc::c_int)?;
+        #[cfg(not(target_os = "linux"))]
+        {
+            _ = busy_poll;
+            return Err(ErrorKind::Unsupported.into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sockopt {
+    use std::io::{Error, Result as IoResult};
+    use std::os::fd::AsRawFd;
+    use std::{mem, ptr};
+
+    use super::*;
+
+    /// Sets an integer SOL_SOCKET option that socket2 doesn't cover.
+    pub(super) fn set(socket: &UdpSocket, name: libc::c_int, value: libc::c_int) -> IoResult<()> {
+        // SAFETY: The option value is a valid c_int that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                name,
+                ptr::from_ref(&value).cast(),
+                mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    /// Reads an integer SOL_SOCKET option that socket2 doesn't cover.
+    pub(super) fn get(socket: &UdpSocket, name: libc::c_int) -> IoResult<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of_val(&value) as libc::socklen_t;
+
+        // SAFETY: The option value and length are valid for writes and outlive the call
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                name,
+                ptr::from_mut(&mut value).cast(),
+                &mut len,
+            )
+        };
+
+        match result {
+            0 => Ok(value),
+            _ => Err(Error::last_os_error()),
+        }
     }
 }
 
 #[cfg(target_os = "windows")]
 mod time {
-    use std::sync::LazyLock;
-    use std::time::Instant;
+    use std::time::SystemTime;
 
     use super::*;
 
-    static START: LazyLock<Instant> = LazyLock::new(|| Instant::now());
-
+    /// Stamps the packet with the time since the UNIX epoch, like the kernel timestamps on Linux.
     pub(super) fn stamp_meta(meta: &mut RecvMeta) {
         if meta.timestamp.is_none() {
-            meta.timestamp = Some(Instant::now().saturating_duration_since(*START));
+            meta.timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod tx_time {
+    use std::io::{Error, Result as IoResult};
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+    use std::{mem, ptr};
+
+    use super::*;
+
+    pub(super) fn enable(socket: &UdpSocket, enabled: bool) -> IoResult<()> {
+        let flags: libc::c_uint = match enabled {
+            true => {
+                libc::SOF_TIMESTAMPING_TX_SOFTWARE
+                    | libc::SOF_TIMESTAMPING_SOFTWARE
+                    | libc::SOF_TIMESTAMPING_OPT_ID
+                    | libc::SOF_TIMESTAMPING_OPT_TSONLY
+            }
+            false => 0,
+        };
+
+        // SAFETY: The option value is a valid c_uint that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPING,
+                ptr::from_ref(&flags).cast(),
+                mem::size_of_val(&flags) as libc::socklen_t,
+            )
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+
+    /// Reads a single message from the error queue, which may or may not be a TX timestamp.
+    pub(super) fn recv(socket: &UdpSocket) -> IoResult<Option<TxTimestamp>> {
+        // With OPT_TSONLY there's no payload, only control messages (aligned for cmsghdr)
+        let mut control = [0u64; 32];
+
+        // SAFETY: An all-zero msghdr is valid, and we point it at a buffer that outlives it
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        // SAFETY: The socket is valid and msg only references live buffers
+        let result = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_ERRQUEUE) };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut id = None;
+        let mut timestamp = None;
+
+        // SAFETY: The kernel filled in msg's control buffer, which we walk with the CMSG macros
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while cmsg.is_null() == false {
+                let data = libc::CMSG_DATA(cmsg);
+
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    // This is a scm_timestamping, whose first timespec is the software timestamp
+                    (libc::SOL_SOCKET, libc::SO_TIMESTAMPING) => {
+                        let ts = ptr::read_unaligned(data.cast::<libc::timespec>());
+                        timestamp = Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                    }
+                    (libc::SOL_IP, libc::IP_RECVERR) | (libc::SOL_IPV6, libc::IPV6_RECVERR) => {
+                        let err = ptr::read_unaligned(data.cast::<libc::sock_extended_err>());
+                        if err.ee_errno == libc::ENOMSG as u32
+                            && err.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING
+                        {
+                            id = Some(err.ee_data);
+                        }
+                    }
+                    _ => {}
+                }
+
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        match (id, timestamp) {
+            (Some(id), Some(timestamp)) => Ok(Some(TxTimestamp { id, timestamp })),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::io::{Error, Result as IoResult};
+    use std::net::IpAddr;
+    use std::os::fd::AsRawFd;
+    use std::{mem, ptr};
+
+    use socket2::SockAddr;
+
+    use super::*;
+
+    /// Room for every control message we attach: ECN, source address and GSO segment size.
+    const CONTROL_LEN: usize = 16;
+
+    /// Buffers for a single `sendmmsg` call, kept around so that sending doesn't allocate.
+    pub(super) struct Batch {
+        names: Vec<SockAddr>,
+        iovs: Vec<libc::iovec>,
+        controls: Vec<([u64; CONTROL_LEN], usize)>, // In u64s to keep the messages aligned
+        hdrs: Vec<libc::mmsghdr>,
+    }
+
+    impl Batch {
+        pub(super) fn new() -> Self {
+            Self {
+                names: Vec::with_capacity(BATCH_COUNT),
+                iovs: Vec::with_capacity(BATCH_COUNT),
+                controls: Vec::with_capacity(BATCH_COUNT),
+                hdrs: Vec::with_capacity(BATCH_COUNT),
+            }
+        }
+    }
+
+    /// Sends each transmit's contents as its own message with a single `sendmmsg`, returning how
+    /// many the kernel accepted.
+    ///
+    /// Packets are marked with `dscp` alongside their ECN codepoint, and `is_ipv6` says whether
+    /// the socket needs IPv4 destinations mapped. Fails only if the first message couldn't be
+    /// sent.
+    pub(super) fn send<'a>(
+        batch: &mut Batch,
+        socket: &UdpSocket,
+        messages: impl Iterator<Item = (&'a ProtoTransmit, &'a [u8])>,
+        dscp: u8,
+        is_ipv6: bool,
+    ) -> IoResult<usize> {
+        batch.names.clear();
+        batch.iovs.clear();
+        batch.controls.clear();
+        batch.hdrs.clear();
+
+        // Fill everything in first, so that nothing moves once we start taking pointers
+        for (transmit, contents) in messages {
+            let destination = socket_destination(is_ipv6, transmit.destination);
+            batch.names.push(SockAddr::from(destination));
+            batch.iovs.push(libc::iovec {
+                iov_base: contents.as_ptr() as *mut _,
+                iov_len: contents.len(),
+            });
+
+            let mut control = [0; CONTROL_LEN];
+            let segment_size = segment_size(transmit, contents);
+            let control_len = encode_control(transmit, segment_size, dscp, &mut control);
+            batch.controls.push((control, control_len));
+        }
+        debug_assert!(batch.controls.len() <= BATCH_COUNT);
+
+        for (index, (control, control_len)) in batch.controls.iter_mut().enumerate() {
+            // SAFETY: An all-zero msghdr is valid, and we fill in the rest below
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = batch.names[index].as_ptr() as *mut _;
+            hdr.msg_namelen = batch.names[index].len();
+            hdr.msg_iov = &mut batch.iovs[index];
+            hdr.msg_iovlen = 1;
+            if *control_len > 0 {
+                hdr.msg_control = control.as_mut_ptr().cast();
+                hdr.msg_controllen = *control_len as _;
+            }
+
+            batch.hdrs.push(libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            });
+        }
+
+        // SAFETY: Every header points into buffers that outlive the call
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                batch.hdrs.as_mut_ptr(),
+                batch.hdrs.len() as libc::c_uint,
+                0,
+            )
+        };
+
+        match result {
+            -1 => Err(Error::last_os_error()),
+            count => Ok(count as usize),
         }
     }
+
+    /// Writes the control messages for a transmit, returning their total length.
+    fn encode_control(
+        transmit: &ProtoTransmit,
+        segment_size: Option<usize>,
+        dscp: u8,
+        control: &mut [u64; CONTROL_LEN],
+    ) -> usize {
+        let mut len = 0;
+
+        if transmit.ecn.is_some() || dscp != 0 {
+            let ecn = transmit
+                .ecn
+                .map_or(0, |ecn| util::udp_ecn(ecn) as libc::c_int);
+            // This replaces the socket's TOS byte entirely, so it has to carry the DSCP too
+            let tos = ((dscp as libc::c_int) << 2) | ecn;
+
+            // IPv4-mapped destinations go out as IPv4, which ignores the IPv6 traffic class
+            let is_ipv4 = match transmit.destination.ip() {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+            };
+
+            match is_ipv4 {
+                true => push(control, &mut len, libc::IPPROTO_IP, libc::IP_TOS, tos),
+                false => push(
+                    control,
+                    &mut len,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_TCLASS,
+                    tos,
+                ),
+            }
+        }
+
+        match transmit.src_ip {
+            Some(IpAddr::V4(ip)) => {
+                let pktinfo = libc::in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(ip.octets()),
+                    },
+                    ipi_addr: libc::in_addr { s_addr: 0 },
+                };
+                push(
+                    control,
+                    &mut len,
+                    libc::IPPROTO_IP,
+                    libc::IP_PKTINFO,
+                    pktinfo,
+                );
+            }
+            Some(IpAddr::V6(ip)) => {
+                let pktinfo = libc::in6_pktinfo {
+                    ipi6_addr: libc::in6_addr {
+                        s6_addr: ip.octets(),
+                    },
+                    ipi6_ifindex: 0,
+                };
+                push(
+                    control,
+                    &mut len,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_PKTINFO,
+                    pktinfo,
+                );
+            }
+            None => {}
+        }
+
+        if let Some(segment_size) = segment_size {
+            let segment_size = segment_size as u16;
+            push(
+                control,
+                &mut len,
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                segment_size,
+            );
+        }
+
+        len
+    }
+
+    /// Appends a single control message at `len`, advancing it past the message.
+    fn push<T: Copy>(
+        control: &mut [u64; CONTROL_LEN],
+        len: &mut usize,
+        level: libc::c_int,
+        ty: libc::c_int,
+        value: T,
+    ) {
+        // SAFETY: These only compute sizes
+        let (space, cmsg_len) = unsafe {
+            let size = mem::size_of::<T>() as libc::c_uint;
+            (
+                libc::CMSG_SPACE(size) as usize,
+                libc::CMSG_LEN(size) as usize,
+            )
+        };
+        assert!(
+            *len + space <= mem::size_of_val(control),
+            "control buffer too small"
+        );
+
+        // SAFETY: We checked the message fits, and the buffer is aligned for cmsghdr
+        unsafe {
+            let cmsg = control
+                .as_mut_ptr()
+                .cast::<u8>()
+                .add(*len)
+                .cast::<libc::cmsghdr>();
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = ty;
+            (*cmsg).cmsg_len = cmsg_len as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), value);
+        }
+
+        *len += space;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket as StdUdpSocket;
+    use std::thread;
+
+    use mio::Poll;
+    use quinn_proto::EcnCodepoint;
+
+    use super::*;
+
+    fn bind(addr: &str, config: &SocketConfig) -> Socket {
+        Socket::new(addr.parse().unwrap(), 1500, config).unwrap()
+    }
+
+    fn push(outbound: &mut Outbound, destination: SocketAddr, contents: &[u8]) {
+        push_for(outbound, None, destination, contents);
+    }
+
+    fn push_for(
+        outbound: &mut Outbound,
+        connection: Option<ConnectionHandle>,
+        destination: SocketAddr,
+        contents: &[u8],
+    ) {
+        let mut buf = outbound.take_buffer();
+        buf.extend_from_slice(contents);
+
+        let transmit = ProtoTransmit {
+            destination,
+            ecn: None,
+            size: contents.len(),
+            segment_size: None,
+            src_ip: None,
+        };
+        outbound.push(0, connection, transmit, buf);
+    }
+
+    #[test]
+    fn maps_ipv4_destinations_on_ipv6_sockets() {
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:443".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+        assert_eq!(socket_destination(true, v4), mapped);
+        assert_eq!(socket_destination(true, mapped), mapped);
+        assert_eq!(socket_destination(true, v6), v6);
+        assert_eq!(socket_destination(false, v4), v4);
+    }
+
+    #[test]
+    fn dual_stack_sends_to_ipv4_on_every_path() {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let config = SocketConfig {
+            only_v6: Some(false),
+            ..Default::default()
+        };
+        let mut socket = bind("[::]:0", &config);
+
+        let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let destination = peer.local_addr().unwrap();
+        assert!(destination.is_ipv4());
+
+        // A lone transmit, and then a batch
+        push(&mut outbound, destination, b"lone");
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        for contents in [&b"first"[..], b"second"] {
+            push(&mut outbound, destination, contents);
+        }
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+
+        let mut buf = [0; 64];
+        for expected in [&b"lone"[..], b"first", b"second"] {
+            let len = peer.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], expected);
+        }
+    }
+
+    /// Waits for the socket to receive a single datagram.
+    fn recv_one(socket: &mut Socket) -> (BytesMut, RecvMeta) {
+        for _ in 0..100 {
+            let mut received = None;
+            socket
+                .recv_all(|data, meta| received = Some((data, *meta)))
+                .unwrap();
+            if let Some(received) = received {
+                return received;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("nothing received");
+    }
+
+    /// Sends a datagram from `client` to `server_addr`, checking that the server sees where it
+    /// came from and where it was sent, and that a reply from there gets back.
+    fn round_trip(socket: &mut Socket, client: &StdUdpSocket, server_addr: SocketAddr) {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let client_addr = client.local_addr().unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        client.send_to(b"ping", server_addr).unwrap();
+        let (data, meta) = recv_one(socket);
+        assert_eq!(&data[..], b"ping");
+
+        // IPv4 clients of a dual-stack socket show up under IPv4-mapped addresses
+        let src = SocketAddr::new(meta.addr.ip().to_canonical(), meta.addr.port());
+        assert_eq!(src, client_addr);
+        let dst_ip = meta.dst_ip.map(|ip| ip.to_canonical());
+        assert_eq!(dst_ip, Some(server_addr.ip()));
+
+        // Replies go back from the address the client sent to
+        let mut buf = outbound.take_buffer();
+        buf.extend_from_slice(b"pong");
+        let transmit = ProtoTransmit {
+            destination: meta.addr,
+            ecn: None,
+            size: 4,
+            segment_size: None,
+            src_ip: meta.dst_ip,
+        };
+        outbound.push(0, None, transmit, buf);
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+
+        let mut buf = [0; 64];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, server_addr);
+    }
+
+    #[test]
+    fn ipv6_loopback_round_trip() {
+        let mut socket = bind("[::1]:0", &SocketConfig::default());
+        let port = socket.sock_mio.local_addr().unwrap().port();
+
+        let client = StdUdpSocket::bind("[::1]:0").unwrap();
+        round_trip(
+            &mut socket,
+            &client,
+            SocketAddr::new("::1".parse().unwrap(), port),
+        );
+    }
+
+    #[test]
+    fn dual_stack_round_trip() {
+        let config = SocketConfig {
+            only_v6: Some(false),
+            ..Default::default()
+        };
+        let mut socket = bind("[::]:0", &config);
+        let port = socket.sock_mio.local_addr().unwrap().port();
+
+        let v4_client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let v4_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        round_trip(&mut socket, &v4_client, v4_addr);
+
+        let v6_client = StdUdpSocket::bind("[::1]:0").unwrap();
+        let v6_addr = SocketAddr::new("::1".parse().unwrap(), port);
+        round_trip(&mut socket, &v6_client, v6_addr);
+    }
+
+    #[test]
+    fn ipv6_only_sockets_ignore_ipv4() {
+        let config = SocketConfig {
+            only_v6: Some(true),
+            ..Default::default()
+        };
+        let mut socket = bind("[::]:0", &config);
+        let port = socket.sock_mio.local_addr().unwrap().port();
+        assert_eq!(socket.options().unwrap().only_v6, Some(true));
+
+        let v4_client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        _ = v4_client.send_to(b"ping", ("127.0.0.1", port));
+        let v6_client = StdUdpSocket::bind("[::1]:0").unwrap();
+        v6_client.send_to(b"ping", ("::1", port)).unwrap();
+
+        // Only the IPv6 datagram arrives, so it's the first thing we read
+        let (_, meta) = recv_one(&mut socket);
+        assert_eq!(meta.addr, v6_client.local_addr().unwrap());
+
+        let mut received = 0;
+        socket.recv_all(|_, _| received += 1).unwrap();
+        assert_eq!(received, 0);
+    }
+
+    /// Turns on an integer IPPROTO_IP option.
+    #[cfg(target_os = "linux")]
+    fn sockopt_ip(socket: &StdUdpSocket, name: libc::c_int) {
+        use std::os::fd::AsRawFd;
+
+        let value: libc::c_int = 1;
+        // SAFETY: The option value is a valid c_int that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                name,
+                std::ptr::from_ref(&value).cast(),
+                mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0, "{:?}", Error::last_os_error());
+    }
+
+    /// Receives a datagram along with the TOS byte it arrived with.
+    #[cfg(target_os = "linux")]
+    fn recv_tos(socket: &StdUdpSocket, buf: &mut [u8]) -> (usize, u8) {
+        use std::os::fd::AsRawFd;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 16];
+
+        // SAFETY: An all-zero msghdr is valid, and we point it at buffers that outlive it
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        // SAFETY: The socket is valid and msg only references live buffers
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        assert!(len >= 0, "{:?}", Error::last_os_error());
+
+        // SAFETY: The kernel filled in the control buffer, which we walk with the CMSG macros
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while cmsg.is_null() == false {
+                if ((*cmsg).cmsg_level, (*cmsg).cmsg_type) == (libc::IPPROTO_IP, libc::IP_TOS) {
+                    return (len as usize, *libc::CMSG_DATA(cmsg));
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        panic!("no TOS on the received datagram");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dscp_is_set_on_every_path() {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let config = SocketConfig {
+            dscp: Some(46),
+            ..Default::default()
+        };
+        let mut socket = bind("127.0.0.1:0", &config);
+        assert!(socket.options().unwrap().dscp_per_packet);
+
+        let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        sockopt_ip(&peer, libc::IP_RECVTOS);
+        let destination = peer.local_addr().unwrap();
+
+        // A lone transmit, a batch, and a GSO batch split into segments
+        push(&mut outbound, destination, b"lone");
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        for contents in [&b"first"[..], b"second"] {
+            push(&mut outbound, destination, contents);
+        }
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        socket.gso = false;
+        let transmit = ProtoTransmit {
+            destination,
+            ecn: Some(EcnCodepoint::Ect0),
+            size: 8,
+            segment_size: Some(4),
+            src_ip: None,
+        };
+        socket.try_send(&transmit, b"seg1seg2").unwrap();
+
+        let mut buf = [0; 64];
+        for expected in [&b"lone"[..], b"first", b"second", b"seg1", b"seg2"] {
+            let (len, tos) = recv_tos(&peer, &mut buf);
+            assert_eq!(&buf[..len], expected);
+            assert_eq!(tos >> 2, 46, "wrong DSCP on {:?}", expected);
+        }
+    }
+
+    #[test]
+    fn only_gso_errors_disable_gso() {
+        let error = |code| Error::from_raw_os_error(code);
+        assert!(gso_unsupported(&error(libc::EIO), false));
+        assert!(gso_unsupported(&error(libc::EIO), true));
+        assert!(gso_unsupported(&error(libc::EINVAL), false));
+        assert!(gso_unsupported(&error(libc::EINVAL), true) == false);
+        assert!(gso_unsupported(&error(libc::EACCES), false) == false);
+        assert!(gso_unsupported(&ErrorKind::WouldBlock.into(), false) == false);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keeps_gso_after_other_errors() {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let mut socket = bind("127.0.0.1:0", &SocketConfig::default());
+        if socket.can_gso() == false {
+            return;
+        }
+
+        // Broadcasting without SO_BROADCAST fails with EACCES, which has nothing to do with GSO
+        let destination = "255.255.255.255:4433".parse().unwrap();
+        let mut buf = outbound.take_buffer();
+        buf.extend_from_slice(b"seg1seg2");
+        let transmit = ProtoTransmit {
+            destination,
+            ecn: None,
+            size: buf.len(),
+            segment_size: Some(4),
+            src_ip: None,
+        };
+        outbound.push(0, None, transmit, buf);
+
+        let mut errors = Vec::new();
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| errors.push(e.kind()))
+            .unwrap();
+        assert_eq!(errors, [ErrorKind::PermissionDenied]);
+        assert!(socket.gso);
+        assert_eq!(socket.pending_sends(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tx_ids_match_timestamps() {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let mut socket = bind("127.0.0.1:0", &SocketConfig::default());
+        socket.set_tx_timestamping(true).unwrap();
+
+        let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let destination = peer.local_addr().unwrap();
+        let connections = [None, Some(ConnectionHandle(1)), Some(ConnectionHandle(2))];
+        for (connection, contents) in connections.into_iter().zip([&b"one"[..], b"two", b"three"]) {
+            push_for(&mut outbound, connection, destination, contents);
+        }
+
+        let sent = socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap()
+            .to_vec();
+        let ids: Vec<_> = sent.iter().map(|sent| sent.tx_ids).collect();
+        let sizes: Vec<_> = sent.iter().map(|sent| sent.size).collect();
+        assert_eq!(
+            ids,
+            (0..3)
+                .map(|first| TxIds { first, count: 1 })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(sizes, [3, 3, 5]);
+
+        // Each timestamp can be traced back to the connection that sent the packet
+        let sent_by: Vec<_> = sent.iter().map(|sent| sent.connection).collect();
+        assert_eq!(sent_by, connections);
+
+        let mut timestamps = Vec::new();
+        for _ in 0..100 {
+            socket
+                .recv_tx_timestamps(|tx| timestamps.push(tx.id))
+                .unwrap();
+            if timestamps.len() >= 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(timestamps, [0, 1, 2]);
+    }
+
+    #[test]
+    fn queues_sends_until_writable() {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let mut socket = bind("127.0.0.1:0", &SocketConfig::default());
+        poll.registry()
+            .register(&mut socket, Token(0), Interest::READABLE)
+            .unwrap();
+
+        let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let destination = peer.local_addr().unwrap();
+
+        // While the socket is full, sends wait and we ask to hear when it's writable
+        socket.full = true;
+        push(&mut outbound, destination, b"first");
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        assert_eq!(socket.pending_sends(), 1);
+        assert_eq!(socket.send_stats().deferred, 1);
+        let writable = Interest::READABLE.add(Interest::WRITABLE);
+        assert_eq!(socket.registered, Some((Token(0), writable)));
+
+        // Anything sent later goes out after what's waiting, and then we stop asking
+        socket.full = false;
+        push(&mut outbound, destination, b"second");
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        assert_eq!(socket.pending_sends(), 0);
+        assert_eq!(socket.registered, Some((Token(0), Interest::READABLE)));
+
+        let mut buf = [0; 64];
+        for expected in [&b"first"[..], b"second"] {
+            let len = peer.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], expected);
+        }
+    }
+
+    #[test]
+    fn drops_the_oldest_sends_past_the_limit() {
+        let poll = Poll::new().unwrap();
+        let mut outbound = Outbound::new(1500);
+        let mut socket = bind("127.0.0.1:0", &SocketConfig::default());
+        poll.registry()
+            .register(&mut socket, Token(0), Interest::READABLE)
+            .unwrap();
+
+        let peer = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let destination = peer.local_addr().unwrap();
+
+        socket.full = true;
+        let overflow = 10;
+        for index in 0..(MAX_PENDING_SENDS + overflow) as u32 {
+            push(&mut outbound, destination, &index.to_be_bytes());
+        }
+        socket
+            .send_all(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        assert_eq!(socket.pending_sends(), MAX_PENDING_SENDS);
+        let stats = socket.send_stats();
+        assert_eq!(stats.deferred, (MAX_PENDING_SENDS + overflow) as u64);
+        assert_eq!(stats.dropped, overflow as u64);
+
+        // Once the socket is writable again, the newest ones go out, starting from the oldest left
+        socket.full = false;
+        socket
+            .flush(poll.registry(), &mut outbound, 0, |e| panic!("{:?}", e))
+            .unwrap();
+        assert_eq!(socket.pending_sends(), 0);
+        assert_eq!(socket.registered, Some((Token(0), Interest::READABLE)));
+
+        let mut buf = [0; 4];
+        peer.recv(&mut buf).unwrap();
+        assert_eq!(u32::from_be_bytes(buf), overflow as u32);
+    }
 }
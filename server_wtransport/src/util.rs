@@ -1,3 +1,5 @@
+use std::time::{Duration, Instant, SystemTime};
+
 use quinn_proto::EcnCodepoint as ProtoEcnCodepoint;
 use quinn_udp::EcnCodepoint as UdpEcnCodepoint;
 
@@ -18,3 +20,16 @@ pub fn udp_ecn(ecn: ProtoEcnCodepoint) -> UdpEcnCodepoint {
         ProtoEcnCodepoint::Ce => UdpEcnCodepoint::Ce,
     }
 }
+
+/// Converts a socket timestamp (measured from the UNIX epoch) to the `Instant` domain.
+///
+/// The two clocks are sampled back to back, so this is only as precise as the gap between them.
+pub fn timestamp_to_instant(timestamp: Duration) -> Option<Instant> {
+    let wall = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    let now = Instant::now();
+
+    // A wall clock that stepped backwards makes the packet look like it's from the future
+    now.checked_sub(wall.saturating_sub(timestamp))
+}
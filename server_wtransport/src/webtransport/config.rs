@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::webtransport::settings::{self, H3Settings, WebTransportDraft};
+use crate::webtransport::{HttpRequest, HttpResponse, frame};
+
+/// Default time a client has after connecting to finish setting up its WebTransport session.
+const DEFAULT_SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default limit for SETTINGS (and any other frame) on the peer's control stream.
+const DEFAULT_MAX_CONTROL_FRAME_SIZE: usize = 4 * 1024;
+
+/// Default limit for the encoded header block of a request (including CONNECT).
+const DEFAULT_MAX_HEADER_SIZE: usize = 16 * 1024;
+
+pub type RouteHandler = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+pub struct WebTransportConfig {
+    /// If set, HTTP/3 GET requests for this path are answered with the certificate hashes.
+    pub cert_hash_path: Option<String>,
+    /// How long a client has after connecting to exchange SETTINGS and complete its CONNECT.
+    ///
+    /// Connections that miss this deadline are closed. If `None`, only the idle timeout applies.
+    pub setup_timeout: Option<Duration>,
+    /// The largest frame we'll buffer from the peer's control stream, including its SETTINGS.
+    ///
+    /// Peers that send anything bigger are disconnected with H3_EXCESSIVE_LOAD.
+    pub max_control_frame_size: usize,
+    /// The largest request header block we'll buffer. Larger requests are answered with a 431.
+    pub max_header_size: usize,
+
+    /// Advertised as SETTINGS_MAX_FIELD_SECTION_SIZE, if set.
+    pub max_field_section_size: Option<u64>,
+    /// Whether to advertise HTTP datagram support (SETTINGS_H3_DATAGRAM).
+    pub enable_datagram: bool,
+    /// Whether to send and receive datagrams as DATAGRAM capsules on the CONNECT stream when the
+    /// peer doesn't support HTTP datagrams. Without this, such peers are rejected during setup.
+    ///
+    /// Peers that enable HTTP datagrams without QUIC datagrams are always rejected.
+    pub datagram_capsule_fallback: bool,
+    /// Whether to advertise extended CONNECT (SETTINGS_ENABLE_CONNECT_PROTOCOL).
+    ///
+    /// Clients can't open WebTransport sessions without it.
+    pub enable_connect_protocol: bool,
+    /// The number of concurrent WebTransport sessions we advertise. Zero disables WebTransport.
+    pub webtransport_max_sessions: u64,
+    /// The WebTransport drafts we advertise. Each peer uses the newest one we have in common.
+    pub webtransport_drafts: Vec<WebTransportDraft>,
+    /// Whether to send reserved (GREASE) setting identifiers, frame types and stream types.
+    ///
+    /// This exercises the client's handling of unknown extensions, but it makes the encoded
+    /// SETTINGS nondeterministic.
+    pub grease: bool,
+
+    routes: HashMap<String, RouteHandler>,
+}
+
+impl Default for WebTransportConfig {
+    fn default() -> Self {
+        Self {
+            cert_hash_path: None,
+            setup_timeout: Some(DEFAULT_SETUP_TIMEOUT),
+            max_control_frame_size: DEFAULT_MAX_CONTROL_FRAME_SIZE,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_field_section_size: None,
+            enable_datagram: true,
+            datagram_capsule_fallback: false,
+            enable_connect_protocol: true,
+            webtransport_max_sessions: 1,
+            webtransport_drafts: WebTransportDraft::ALL.to_vec(),
+            grease: false,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl WebTransportConfig {
+    /// Registers a handler for HTTP/3 GET (and HEAD) requests on the given path.
+    ///
+    /// Requests for unregistered paths are answered with a 404.
+    pub fn add_route(
+        &mut self,
+        path: impl Into<String>,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) {
+        self.routes.insert(path.into(), Box::new(handler));
+    }
+
+    /// The SETTINGS we send to every peer.
+    pub(crate) fn h3_settings(&self) -> H3Settings {
+        let mut h3 = H3Settings::default();
+
+        if let Some(size) = self.max_field_section_size {
+            h3.insert(settings::MAX_FIELD_SECTION_SIZE, size);
+        }
+
+        if self.enable_connect_protocol {
+            h3.insert(settings::ENABLE_CONNECT_PROTOCOL, 1);
+        }
+
+        if self.enable_datagram {
+            h3.insert(settings::H3_DATAGRAM, 1);
+            h3.insert(settings::H3_DATAGRAM_DEPRECATED, 1);
+        }
+
+        if self.webtransport_max_sessions > 0 {
+            // Each draft is enabled by its own setting, so peers on any of them can find theirs
+            for &draft in &self.webtransport_drafts {
+                let (id, value) = match draft {
+                    WebTransportDraft::Draft02 => (settings::WEBTRANSPORT_ENABLE_DEPRECATED, 1),
+                    WebTransportDraft::Draft07 => (
+                        settings::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED,
+                        self.webtransport_max_sessions,
+                    ),
+                    WebTransportDraft::Latest => (
+                        settings::WEBTRANSPORT_MAX_SESSIONS,
+                        self.webtransport_max_sessions,
+                    ),
+                };
+
+                h3.insert(id, value);
+            }
+        }
+
+        if self.grease {
+            // The value of a reserved setting is meaningless, so reuse the randomness
+            let id = frame::grease();
+            h3.insert(id, id >> 8);
+        }
+
+        h3
+    }
+
+    pub(crate) fn route(&self, path: &str) -> Option<&RouteHandler> {
+        self.routes.get(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::VarInt;
+    use quinn_proto::coding::Codec;
+
+    use super::*;
+
+    fn encoded_settings(config: &WebTransportConfig) -> Vec<u8> {
+        let mut buf = Vec::new();
+        config.h3_settings().encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn default_settings_are_golden() {
+        #[rustfmt::skip]
+        let expected = [
+            0x00,                                           // Control stream type
+            0x04, 0x1c,                                     // SETTINGS frame, 28 bytes
+            0x08, 0x01,                                     // ENABLE_CONNECT_PROTOCOL
+            0x33, 0x01,                                     // H3_DATAGRAM
+            0x80, 0xff, 0xd2, 0x77, 0x01,                   // H3_DATAGRAM (deprecated)
+            0xab, 0x60, 0x37, 0x42, 0x01,                   // ENABLE_WEBTRANSPORT (draft 02)
+            0xab, 0x60, 0x37, 0x43, 0x01,                   // WEBTRANSPORT_MAX_SESSIONS (draft 07)
+            0xc0, 0x00, 0x00, 0x00, 0xc6, 0x71, 0x70, 0x6a, // WT_MAX_SESSIONS
+            0x01,
+        ];
+
+        assert_eq!(encoded_settings(&WebTransportConfig::default()), expected);
+    }
+
+    #[test]
+    fn custom_settings_are_golden() {
+        let config = WebTransportConfig {
+            max_field_section_size: Some(16 * 1024),
+            enable_datagram: false,
+            webtransport_max_sessions: 4,
+            webtransport_drafts: vec![WebTransportDraft::Latest],
+            ..Default::default()
+        };
+
+        #[rustfmt::skip]
+        let expected = [
+            0x00,                                           // Control stream type
+            0x04, 0x10,                                     // SETTINGS frame, 16 bytes
+            0x06, 0x80, 0x00, 0x40, 0x00,                   // MAX_FIELD_SECTION_SIZE
+            0x08, 0x01,                                     // ENABLE_CONNECT_PROTOCOL
+            0xc0, 0x00, 0x00, 0x00, 0xc6, 0x71, 0x70, 0x6a, // WT_MAX_SESSIONS
+            0x04,
+        ];
+
+        assert_eq!(encoded_settings(&config), expected);
+    }
+
+    /// The identifiers of every setting in an encoded control stream prefix.
+    fn setting_ids(encoded: &[u8]) -> Vec<u64> {
+        let (frame, _) = frame::decode(&encoded[1..]).unwrap();
+        assert_eq!(frame.ty, frame::SETTINGS);
+
+        let mut payload = frame.payload;
+        let mut ids = Vec::new();
+        while payload.is_empty() == false {
+            ids.push(VarInt::decode(&mut payload).unwrap().into_inner());
+            VarInt::decode(&mut payload).unwrap(); // The value
+        }
+
+        ids
+    }
+
+    #[test]
+    fn grease_adds_a_reserved_setting() {
+        let config = WebTransportConfig {
+            grease: true,
+            ..Default::default()
+        };
+
+        let ids = setting_ids(&encoded_settings(&config));
+        assert_eq!(ids.iter().filter(|&&id| frame::is_grease(id)).count(), 1);
+    }
+
+    #[test]
+    fn no_reserved_settings_without_grease() {
+        let ids = setting_ids(&encoded_settings(&WebTransportConfig::default()));
+        assert!(ids.iter().all(|&id| frame::is_grease(id) == false));
+    }
+
+    #[test]
+    fn settings_encode_the_same_every_time() {
+        let config = WebTransportConfig::default();
+        assert_eq!(encoded_settings(&config), encoded_settings(&config));
+    }
+}
@@ -1,7 +1,52 @@
 use quinn_proto::coding::UnexpectedEnd;
-use quinn_proto::{ReadError, SendDatagramError, WriteError};
+use quinn_proto::{ClosedStream, FinishError, ReadError, SendDatagramError, VarInt, WriteError};
 use web_transport_proto::{ConnectError, SettingsError};
 
+// HTTP/3 error codes (RFC 9114, Section 8.1, RFC 9204, Section 6 and RFC 9297, Section 5.2).
+pub const H3_NO_ERROR: VarInt = VarInt::from_u32(0x100);
+pub const H3_GENERAL_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x101);
+pub const H3_INTERNAL_ERROR: VarInt = VarInt::from_u32(0x102);
+pub const H3_STREAM_CREATION_ERROR: VarInt = VarInt::from_u32(0x103);
+pub const H3_CLOSED_CRITICAL_STREAM: VarInt = VarInt::from_u32(0x104);
+pub const H3_FRAME_UNEXPECTED: VarInt = VarInt::from_u32(0x105);
+pub const H3_FRAME_ERROR: VarInt = VarInt::from_u32(0x106);
+pub const H3_EXCESSIVE_LOAD: VarInt = VarInt::from_u32(0x107);
+pub const H3_ID_ERROR: VarInt = VarInt::from_u32(0x108);
+pub const H3_SETTINGS_ERROR: VarInt = VarInt::from_u32(0x109);
+pub const H3_MISSING_SETTINGS: VarInt = VarInt::from_u32(0x10a);
+pub const H3_REQUEST_CANCELLED: VarInt = VarInt::from_u32(0x10c);
+pub const H3_REQUEST_INCOMPLETE: VarInt = VarInt::from_u32(0x10d);
+pub const H3_MESSAGE_ERROR: VarInt = VarInt::from_u32(0x10e);
+pub const H3_DATAGRAM_ERROR: VarInt = VarInt::from_u32(0x33);
+/// Sent when we won't buffer a WebTransport stream until its session is established.
+pub const WEBTRANSPORT_BUFFERED_STREAM_REJECTED: VarInt = VarInt::from_u32(0x3994bd84);
+pub const QPACK_DECOMPRESSION_FAILED: VarInt = VarInt::from_u32(0x200);
+
+/// The first HTTP/3 error code reserved for WebTransport application error codes.
+const WEBTRANSPORT_CODE_FIRST: u64 = 0x52e4a40fa8db;
+
+/// Maps a WebTransport application error code into the HTTP/3 error code space.
+///
+/// The range skips over a reserved (GREASE) code after every 0x1e application codes.
+pub fn webtransport_to_http3(code: u32) -> VarInt {
+    let code = u64::from(code);
+    VarInt::from_u64(WEBTRANSPORT_CODE_FIRST + code + code / 0x1e)
+        .expect("webtransport code range fits in a varint")
+}
+
+/// Maps an HTTP/3 error code back to a WebTransport application error code, if it is one.
+pub fn http3_to_webtransport(code: VarInt) -> Option<u32> {
+    let code = code.into_inner();
+    let shifted = code.checked_sub(WEBTRANSPORT_CODE_FIRST)?;
+
+    // Reserved codes are interleaved with the real ones, and never map back
+    if (code - 0x21).is_multiple_of(0x1f) {
+        return None;
+    }
+
+    u32::try_from(shifted - shifted / 0x1f).ok()
+}
+
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum WebTransportError {
     #[error("quic stream was closed early")]
@@ -10,15 +55,55 @@ pub enum WebTransportError {
     UnexpectedSessionId,
     #[error("webtransport is not supported")]
     WebTransportUnsupported,
+    #[error("extended connect is not supported")]
+    ExtendedConnectUnsupported,
+    #[error("http/3 datagrams are not supported")]
+    H3DatagramUnsupported,
+    #[error("quic datagrams are not supported")]
+    QuicDatagramUnsupported,
     #[error("webtransport is not connected")]
     WebTransportNotConnected,
+    #[error("webtransport session was closed")]
+    SessionClosed,
     #[error("not ready to respond")]
     NotReadyToRespond,
+    #[error("webtransport setup timed out")]
+    SetupTimeout,
+    #[error("unexpected frame type {0:#x}")]
+    UnexpectedFrame(u64),
+    #[error("malformed frame of type {0:#x}")]
+    MalformedFrame(u64),
+    #[error("invalid id {1} in frame of type {0:#x}")]
+    InvalidFrameId(u64, u64),
+    #[error("qpack decompression failed")]
+    QpackDecompressionFailed,
+    #[error("malformed http request")]
+    MalformedRequest,
+    #[error("request headers too large")]
+    HeadersTooLarge,
+    #[error("peer exceeded a buffer limit")]
+    ExcessiveLoad,
+    #[error("invalid settings")]
+    InvalidSettings,
+    #[error("first control frame was not settings")]
+    MissingSettings,
+    #[error("critical stream was closed")]
+    ClosedCriticalStream,
+    #[error("unexpected unidirectional stream type {0:#x}")]
+    UnexpectedStreamType(u64),
+    #[error("duplicate unidirectional stream type {0:#x}")]
+    DuplicateStreamType(u64),
+    #[error("error code {0:#x} is not a webtransport error code")]
+    UnmappedErrorCode(u64),
 
     #[error("read error: {0}")]
     ReadError(#[from] ReadError),
     #[error("write error: {0}")]
     WriteError(#[from] WriteError),
+    #[error("finish error: {0}")]
+    FinishError(#[from] FinishError),
+    #[error("closed stream: {0}")]
+    ClosedStream(#[from] ClosedStream),
     #[error("send datagram error: {0}")]
     SendDatagramError(#[from] SendDatagramError),
     #[error("settings error: {0}")]
@@ -27,8 +112,98 @@ pub enum WebTransportError {
     ConnectError(#[from] ConnectError),
 }
 
+impl WebTransportError {
+    /// The HTTP/3 error code to close the connection with when this error occurs.
+    pub fn code(&self) -> VarInt {
+        match self {
+            WebTransportError::UnexpectedEnd => H3_GENERAL_PROTOCOL_ERROR,
+            WebTransportError::UnexpectedSessionId => H3_DATAGRAM_ERROR,
+            WebTransportError::WebTransportUnsupported => H3_SETTINGS_ERROR,
+            WebTransportError::ExtendedConnectUnsupported => H3_SETTINGS_ERROR,
+            WebTransportError::H3DatagramUnsupported => H3_SETTINGS_ERROR,
+            WebTransportError::QuicDatagramUnsupported => H3_SETTINGS_ERROR,
+            WebTransportError::WebTransportNotConnected => H3_INTERNAL_ERROR,
+            WebTransportError::SessionClosed => H3_NO_ERROR,
+            WebTransportError::NotReadyToRespond => H3_INTERNAL_ERROR,
+            WebTransportError::SetupTimeout => H3_NO_ERROR,
+            WebTransportError::UnexpectedFrame(_) => H3_FRAME_UNEXPECTED,
+            WebTransportError::MalformedFrame(_) => H3_FRAME_ERROR,
+            WebTransportError::InvalidFrameId(_, _) => H3_ID_ERROR,
+            WebTransportError::QpackDecompressionFailed => QPACK_DECOMPRESSION_FAILED,
+            WebTransportError::MalformedRequest => H3_MESSAGE_ERROR,
+            WebTransportError::HeadersTooLarge => H3_EXCESSIVE_LOAD,
+            WebTransportError::ExcessiveLoad => H3_EXCESSIVE_LOAD,
+            WebTransportError::InvalidSettings => H3_SETTINGS_ERROR,
+            WebTransportError::MissingSettings => H3_MISSING_SETTINGS,
+            WebTransportError::ClosedCriticalStream => H3_CLOSED_CRITICAL_STREAM,
+            WebTransportError::UnexpectedStreamType(_) => H3_STREAM_CREATION_ERROR,
+            WebTransportError::DuplicateStreamType(_) => H3_STREAM_CREATION_ERROR,
+            WebTransportError::UnmappedErrorCode(_) => H3_GENERAL_PROTOCOL_ERROR,
+
+            // Request streams handle their own read and write errors, so these only ever reach
+            // the connection from one of the streams it can't live without
+            WebTransportError::ReadError(_) => H3_CLOSED_CRITICAL_STREAM,
+            WebTransportError::WriteError(_) => H3_CLOSED_CRITICAL_STREAM,
+            WebTransportError::FinishError(_) => H3_CLOSED_CRITICAL_STREAM,
+            WebTransportError::ClosedStream(_) => H3_INTERNAL_ERROR,
+
+            WebTransportError::SendDatagramError(_) => H3_INTERNAL_ERROR,
+            WebTransportError::SettingsError(_) => H3_SETTINGS_ERROR,
+            WebTransportError::ConnectError(_) => H3_MESSAGE_ERROR,
+        }
+    }
+}
+
 impl From<UnexpectedEnd> for WebTransportError {
     fn from(_: UnexpectedEnd) -> Self {
         WebTransportError::UnexpectedEnd
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webtransport_codes_round_trip() {
+        for code in [0, 42, 0x1d, 0x1e, u32::MAX] {
+            let mapped = webtransport_to_http3(code);
+            assert_eq!(http3_to_webtransport(mapped), Some(code), "{:#x}", code);
+        }
+
+        assert_eq!(
+            webtransport_to_http3(0).into_inner(),
+            WEBTRANSPORT_CODE_FIRST
+        );
+    }
+
+    #[test]
+    fn reserved_codes_are_skipped() {
+        let last = webtransport_to_http3(u32::MAX).into_inner();
+
+        // Every reserved codepoint between the first and last mapped codes
+        let first_reserved = WEBTRANSPORT_CODE_FIRST + 0x1e;
+        assert!((first_reserved - 0x21).is_multiple_of(0x1f));
+        let last_reserved = first_reserved + (last - first_reserved) / 0x1f * 0x1f;
+        let reserved = (first_reserved..last).step_by(0x1f).take(1000);
+        for code in reserved.chain([last_reserved]) {
+            assert_eq!(http3_to_webtransport(VarInt::from_u64(code).unwrap()), None);
+        }
+
+        // Nothing maps onto a reserved codepoint, either side of one
+        for code in [0x1c, 0x1d, 0x1e, 0x1f, 42, u32::MAX] {
+            let mapped = webtransport_to_http3(code).into_inner();
+            assert!((mapped - 0x21).is_multiple_of(0x1f) == false, "{:#x}", code);
+        }
+    }
+
+    #[test]
+    fn codes_outside_the_range_are_unmapped() {
+        assert_eq!(http3_to_webtransport(H3_NO_ERROR), None);
+        let before = VarInt::from_u64(WEBTRANSPORT_CODE_FIRST - 1).unwrap();
+        assert_eq!(http3_to_webtransport(before), None);
+
+        let past = webtransport_to_http3(u32::MAX).into_inner() + 1;
+        assert_eq!(http3_to_webtransport(VarInt::from_u64(past).unwrap()), None);
+    }
+}
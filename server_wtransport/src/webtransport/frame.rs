@@ -0,0 +1,137 @@
+use quinn_proto::VarInt;
+use quinn_proto::coding::Codec;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::webtransport::WebTransportError;
+
+// HTTP/3 frame types (RFC 9114, Section 7.2).
+pub const DATA: u64 = 0x00;
+pub const HEADERS: u64 = 0x01;
+pub const CANCEL_PUSH: u64 = 0x03;
+pub const SETTINGS: u64 = 0x04;
+pub const PUSH_PROMISE: u64 = 0x05;
+pub const GOAWAY: u64 = 0x07;
+pub const MAX_PUSH_ID: u64 = 0x0d;
+
+// HTTP/3 unidirectional stream types (RFC 9114, Section 6.2 and RFC 9204, Section 4.2).
+pub const STREAM_CONTROL: u64 = 0x00;
+pub const STREAM_PUSH: u64 = 0x01;
+pub const STREAM_QPACK_ENCODER: u64 = 0x02;
+pub const STREAM_QPACK_DECODER: u64 = 0x03;
+pub const STREAM_WEBTRANSPORT: u64 = 0x54;
+
+/// Starts a WebTransport bidirectional stream in place of a frame type, followed by the session ID.
+pub const WEBTRANSPORT_STREAM: u64 = 0x41;
+
+/// A frame header is two varints (type and length), which take at most 8 bytes each.
+pub const MAX_FRAME_HEADER_SIZE: usize = 16;
+
+pub struct Frame<'a> {
+    pub ty: u64,
+    pub payload: &'a [u8],
+}
+
+/// Decodes a single frame from the front of `buf`, along with the number of bytes it occupies.
+///
+/// Returns `None` if `buf` does not yet contain the whole frame.
+pub fn decode(buf: &[u8]) -> Option<(Frame<'_>, usize)> {
+    let mut cursor = buf;
+    let ty = VarInt::decode(&mut cursor).ok()?.into_inner();
+    let len = VarInt::decode(&mut cursor).ok()?.into_inner();
+
+    let len = usize::try_from(len).ok()?;
+    let payload = cursor.get(..len)?;
+    let size = (buf.len() - cursor.len()) + len;
+
+    Some((Frame { ty, payload }, size))
+}
+
+/// Returns true for frame types that are only valid on the control stream.
+pub fn is_control(ty: u64) -> bool {
+    matches!(ty, CANCEL_PUSH | SETTINGS | GOAWAY | MAX_PUSH_ID)
+}
+
+/// Returns true for HTTP/2 frame types, which are reserved and must never be sent in HTTP/3.
+pub fn is_reserved_http2(ty: u64) -> bool {
+    matches!(ty, 0x02 | 0x06 | 0x08 | 0x09)
+}
+
+/// Decodes a frame payload that consists of exactly one varint (e.g. GOAWAY or MAX_PUSH_ID).
+pub fn decode_varint_payload(ty: u64, mut payload: &[u8]) -> Result<u64, WebTransportError> {
+    match VarInt::decode(&mut payload) {
+        Ok(value) if payload.is_empty() => Ok(value.into_inner()),
+        _ => Err(WebTransportError::MalformedFrame(ty)),
+    }
+}
+
+/// Picks a random reserved value of the form `0x1f * N + 0x21` (RFC 9114, Section 7.2.8).
+///
+/// The same reserved space is used to GREASE setting identifiers, frame types and stream types.
+pub fn grease() -> u64 {
+    let mut bytes = [0; 4];
+    _ = SystemRandom::new().fill(&mut bytes);
+
+    // Keeping N within 32 bits means the result always fits in a varint
+    let n = u64::from(u32::from_le_bytes(bytes));
+    0x1f * n + 0x21
+}
+
+pub fn encode(ty: u64, payload: &[u8], buf: &mut Vec<u8>) {
+    encode_varint(ty, buf);
+    encode_varint(payload.len() as u64, buf);
+    buf.extend_from_slice(payload);
+}
+
+pub fn encode_varint(value: u64, buf: &mut Vec<u8>) {
+    VarInt::from_u64(value)
+        .expect("varint out of range")
+        .encode(buf);
+}
+
+/// Whether a value is one of the reserved GREASE values, for checking what we send.
+#[cfg(test)]
+pub fn is_grease(value: u64) -> bool {
+    value >= 0x21 && (value - 0x21).is_multiple_of(0x1f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grease_values_are_reserved() {
+        for _ in 0..1000 {
+            let value = grease();
+            assert!(is_grease(value), "{:#x} is not reserved", value);
+            assert!(
+                VarInt::from_u64(value).is_ok(),
+                "{:#x} doesn't fit a varint",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn known_types_are_not_grease() {
+        let types = [
+            DATA,
+            HEADERS,
+            CANCEL_PUSH,
+            SETTINGS,
+            PUSH_PROMISE,
+            GOAWAY,
+            MAX_PUSH_ID,
+        ];
+        assert!(types.into_iter().all(|ty| is_grease(ty) == false));
+
+        let streams = [
+            STREAM_CONTROL,
+            STREAM_PUSH,
+            STREAM_QPACK_ENCODER,
+            STREAM_QPACK_DECODER,
+            STREAM_WEBTRANSPORT,
+            WEBTRANSPORT_STREAM,
+        ];
+        assert!(streams.into_iter().all(|ty| is_grease(ty) == false));
+    }
+}
@@ -0,0 +1,138 @@
+use bytes::Bytes;
+use http::{Method, StatusCode};
+use url::Url;
+
+use crate::webtransport::WebTransportError;
+use crate::webtransport::{frame, qpack};
+
+/// The pseudo-header fields of an incoming HTTP/3 request.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub scheme: Option<String>,
+    pub authority: Option<String>,
+    pub path: Option<String>,
+    pub protocol: Option<String>,
+}
+
+/// A complete response to a simple (non-CONNECT) HTTP/3 request.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub content_type: Option<&'static str>,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Bytes,
+}
+
+impl HttpRequest {
+    pub(crate) fn decode(encoded: &[u8]) -> Result<Self, WebTransportError> {
+        let mut method = None;
+        let mut scheme = None;
+        let mut authority = None;
+        let mut path = None;
+        let mut protocol = None;
+
+        for (name, value) in qpack::decode(encoded)? {
+            match name.as_str() {
+                ":method" => method = Some(value),
+                ":scheme" => scheme = Some(value),
+                ":authority" => authority = Some(value),
+                ":path" => path = Some(value),
+                ":protocol" => protocol = Some(value),
+                _ => {} // We don't use any regular header fields
+            }
+        }
+
+        let method = method
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            .ok_or(WebTransportError::MalformedRequest)?;
+
+        Ok(Self {
+            method,
+            scheme,
+            authority,
+            path,
+            protocol,
+        })
+    }
+
+    /// Returns the target URL if this is a well-formed WebTransport extended CONNECT request.
+    pub(crate) fn webtransport_url(&self) -> Option<Url> {
+        if self.method != Method::CONNECT || self.protocol.as_deref() != Some("webtransport") {
+            return None;
+        }
+
+        let (Some(scheme), Some(authority), Some(path)) = (
+            self.scheme.as_deref(),
+            self.authority.as_deref(),
+            self.path.as_deref(),
+        ) else {
+            return None;
+        };
+
+        match scheme {
+            "https" => Url::parse(&format!("{}://{}{}", scheme, authority, path)).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            content_type: None,
+            headers: Vec::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    pub fn json(body: impl Into<Bytes>) -> Self {
+        Self::with_body(StatusCode::OK, "application/json", body)
+    }
+
+    pub fn with_body(
+        status: StatusCode,
+        content_type: &'static str,
+        body: impl Into<Bytes>,
+    ) -> Self {
+        Self {
+            status,
+            content_type: Some(content_type),
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Encodes the response as a HEADERS frame followed by a DATA frame (if there is a body).
+    ///
+    /// Responses to HEAD requests keep their content-length but leave out the DATA frame.
+    pub(crate) fn encode(&self, method: &Method, buf: &mut Vec<u8>) {
+        let content_length = self.body.len().to_string();
+        let mut fields = vec![
+            (":status", self.status.as_str()),
+            ("content-length", content_length.as_str()),
+        ];
+
+        if let Some(content_type) = self.content_type {
+            fields.push(("content-type", content_type));
+        }
+
+        for (name, value) in &self.headers {
+            fields.push((name, value.as_str()));
+        }
+
+        let mut headers = Vec::new();
+        qpack::encode(fields, &mut headers);
+        frame::encode(frame::HEADERS, &headers, buf);
+
+        if self.body.is_empty() == false && method != Method::HEAD {
+            frame::encode(frame::DATA, &self.body, buf);
+        }
+    }
+}
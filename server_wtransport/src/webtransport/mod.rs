@@ -1,5 +1,18 @@
+mod config;
 mod error;
+mod frame;
+mod huffman;
+mod message;
+mod qpack;
 mod request;
+mod settings;
+mod stream;
+#[cfg(test)]
+pub(crate) mod testing;
 
-pub use error::WebTransportError;
-pub use request::{Request, RequestState};
+pub use config::WebTransportConfig;
+pub use error::{WebTransportError, http3_to_webtransport, webtransport_to_http3};
+pub use message::{HttpRequest, HttpResponse};
+pub use request::{Capabilities, Request, RequestState, SessionEvent};
+pub use settings::WebTransportDraft;
+pub use stream::{read_to, write_some};
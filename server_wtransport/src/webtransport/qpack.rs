@@ -0,0 +1,342 @@
+use crate::webtransport::WebTransportError;
+use crate::webtransport::huffman;
+
+pub type Field = (String, String);
+
+/// Decodes an encoded field section (the payload of a HEADERS frame).
+///
+/// We advertise a QPACK dynamic table capacity of zero, so only static table references are valid.
+pub fn decode(mut buf: &[u8]) -> Result<Vec<Field>, WebTransportError> {
+    let required_insert_count = decode_int(&mut buf, 8)?;
+    let _delta_base = decode_int(&mut buf, 7)?;
+
+    if required_insert_count != 0 {
+        return Err(WebTransportError::QpackDecompressionFailed); // No dynamic table allowed
+    }
+
+    let mut fields = Vec::new();
+
+    while let Some(&first) = buf.first() {
+        let field = if first & 0b1000_0000 != 0 {
+            // Indexed field line (only static references are valid)
+            if first & 0b0100_0000 == 0 {
+                return Err(WebTransportError::QpackDecompressionFailed);
+            }
+
+            let (name, value) = static_entry(decode_int(&mut buf, 6)?)?;
+            (name.to_owned(), value.to_owned())
+        } else if first & 0b0100_0000 != 0 {
+            // Literal field line with name reference (only static references are valid)
+            if first & 0b0001_0000 == 0 {
+                return Err(WebTransportError::QpackDecompressionFailed);
+            }
+
+            let (name, _) = static_entry(decode_int(&mut buf, 4)?)?;
+            (name.to_owned(), decode_string(&mut buf, 7)?)
+        } else if first & 0b0010_0000 != 0 {
+            // Literal field line with literal name
+            let name = decode_string(&mut buf, 3)?;
+            (name, decode_string(&mut buf, 7)?)
+        } else {
+            // Both post-base representations reference the dynamic table
+            return Err(WebTransportError::QpackDecompressionFailed);
+        };
+
+        fields.push(field);
+    }
+
+    Ok(fields)
+}
+
+/// Encodes a field section using only static table references and plain literals.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>, buf: &mut Vec<u8>) {
+    // Required insert count and delta base are both zero without a dynamic table
+    buf.extend_from_slice(&[0, 0]);
+
+    for (name, value) in fields {
+        let exact = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value));
+        let named = STATIC_TABLE.iter().position(|&(n, _)| n == name);
+
+        match (exact, named) {
+            (Some(index), _) => {
+                encode_int(index as u64, 6, 0b1100_0000, buf);
+            }
+            (None, Some(index)) => {
+                encode_int(index as u64, 4, 0b0101_0000, buf);
+                encode_string(value, 7, 0, buf);
+            }
+            (None, None) => {
+                encode_string(name, 3, 0b0010_0000, buf);
+                encode_string(value, 7, 0, buf);
+            }
+        }
+    }
+}
+
+fn static_entry(index: u64) -> Result<(&'static str, &'static str), WebTransportError> {
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| STATIC_TABLE.get(index).copied())
+        .ok_or(WebTransportError::QpackDecompressionFailed)
+}
+
+fn decode_int(buf: &mut &[u8], prefix: u32) -> Result<u64, WebTransportError> {
+    let mask = (1u64 << prefix) - 1;
+    let (&first, rest) = buf
+        .split_first()
+        .ok_or(WebTransportError::QpackDecompressionFailed)?;
+    *buf = rest;
+
+    let mut value = u64::from(first) & mask;
+    if value < mask {
+        return Ok(value);
+    }
+
+    for shift in (0..63).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or(WebTransportError::QpackDecompressionFailed)?;
+        *buf = rest;
+
+        value += u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(WebTransportError::QpackDecompressionFailed) // Overflow
+}
+
+fn decode_string(buf: &mut &[u8], prefix: u32) -> Result<String, WebTransportError> {
+    let is_huffman = buf.first().is_some_and(|first| first & (1 << prefix) != 0);
+    let len = usize::try_from(decode_int(buf, prefix)?)
+        .ok()
+        .filter(|&len| len <= buf.len())
+        .ok_or(WebTransportError::QpackDecompressionFailed)?;
+
+    let (raw, rest) = buf.split_at(len);
+    *buf = rest;
+
+    let bytes = match is_huffman {
+        true => huffman::decode(raw)?,
+        false => raw.to_vec(),
+    };
+
+    String::from_utf8(bytes).map_err(|_| WebTransportError::QpackDecompressionFailed)
+}
+
+fn encode_int(value: u64, prefix: u32, flags: u8, buf: &mut Vec<u8>) {
+    let mask = (1u64 << prefix) - 1;
+
+    if value < mask {
+        buf.push(flags | value as u8);
+        return;
+    }
+
+    buf.push(flags | mask as u8);
+    let mut remaining = value - mask;
+
+    while remaining >= 0x80 {
+        buf.push((remaining & 0x7f) as u8 | 0x80);
+        remaining >>= 7;
+    }
+
+    buf.push(remaining as u8);
+}
+
+fn encode_string(value: &str, prefix: u32, flags: u8, buf: &mut Vec<u8>) {
+    // We never Huffman-encode, so the H bit (just above the prefix) stays clear
+    encode_int(value.len() as u64, prefix, flags, buf);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// The QPACK static table (RFC 9204, Appendix A).
+static STATIC_TABLE: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_match_rfc7541_examples() {
+        // Appendix C.1
+        let examples: [(u64, u32, &[u8]); 3] = [
+            (10, 5, &[0x0a]),
+            (1337, 5, &[0x1f, 0x9a, 0x0a]),
+            (42, 8, &[0x2a]),
+        ];
+
+        for (value, prefix, encoded) in examples {
+            let mut buf = Vec::new();
+            encode_int(value, prefix, 0, &mut buf);
+            assert_eq!(buf, encoded);
+
+            assert_eq!(decode_int(&mut &encoded[..], prefix).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn strings_match_rfc7541_examples() {
+        // Appendix C.2.1, a plain literal
+        let plain = b"\x0acustom-key";
+        assert_eq!(decode_string(&mut &plain[..], 7).unwrap(), "custom-key");
+
+        let mut buf = Vec::new();
+        encode_string("custom-key", 7, 0, &mut buf);
+        assert_eq!(buf, plain);
+
+        // Appendix C.4.1, a Huffman-encoded literal
+        let huffman = [
+            0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decode_string(&mut &huffman[..], 7).unwrap(),
+            "www.example.com"
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        assert!(decode_int(&mut &[0x1f, 0x9a][..], 5).is_err());
+        assert!(decode_string(&mut &b"\x0acustom"[..], 7).is_err());
+    }
+
+    #[test]
+    fn field_sections_round_trip() {
+        let fields = [
+            (":status", "200"),    // Exact static table match
+            (":status", "299"),    // Static table name reference
+            ("x-custom", "value"), // Literal name
+        ];
+
+        let mut buf = Vec::new();
+        encode(fields, &mut buf);
+        assert_eq!(&buf[..3], [0x00, 0x00, 0xc0 | 25]); // Prefix, then static index 25
+
+        let decoded = decode(&buf).unwrap();
+        let decoded: Vec<_> = decoded
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(decoded, fields);
+    }
+
+    #[test]
+    fn rejects_dynamic_table_references() {
+        // A non-zero required insert count
+        assert!(decode(&[0x01, 0x00]).is_err());
+        // An indexed field line referencing the dynamic table
+        assert!(decode(&[0x00, 0x00, 0x80]).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+
+use quinn_proto::{Connection, Dir, StreamId, VarInt};
+
+use crate::webtransport::error::WEBTRANSPORT_BUFFERED_STREAM_REJECTED;
+
+/// The most WebTransport streams of each direction we'll hold before they're accepted.
+pub const MAX_BUFFERED_STREAMS: usize = 64;
+
+/// WebTransport streams the peer opened, held until the session they belong to accepts them.
+///
+/// Streams may arrive before the CONNECT that creates their session, so they're buffered up to a
+/// limit. Anything past that limit, or for a session other than ours, is rejected.
+pub struct BufferedStreams {
+    streams: VecDeque<(VarInt, StreamId)>,
+}
+
+impl BufferedStreams {
+    pub fn new() -> Self {
+        Self {
+            streams: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, connection: &mut Connection, session_id: VarInt, id: StreamId) {
+        if self.streams.len() >= MAX_BUFFERED_STREAMS {
+            reject(connection, id);
+            return;
+        }
+
+        self.streams.push_back((session_id, id));
+    }
+
+    /// Takes the oldest stream for the given session, if any.
+    pub fn accept(&mut self, session_id: VarInt) -> Option<StreamId> {
+        let index = self.streams.iter().position(|&(s, _)| s == session_id)?;
+        self.streams.remove(index).map(|(_, id)| id)
+    }
+
+    /// Rejects every stream that doesn't belong to the session, which is the only one we'll have.
+    pub fn reject_others(&mut self, connection: &mut Connection, session_id: VarInt) {
+        self.streams.retain(|&(s, id)| {
+            if s != session_id {
+                reject(connection, id);
+            }
+
+            s == session_id
+        });
+    }
+}
+
+fn reject(connection: &mut Connection, id: StreamId) {
+    _ = connection
+        .recv_stream(id)
+        .stop(WEBTRANSPORT_BUFFERED_STREAM_REJECTED);
+
+    if id.dir() == Dir::Bi {
+        _ = connection
+            .send_stream(id)
+            .reset(WEBTRANSPORT_BUFFERED_STREAM_REJECTED);
+    }
+}
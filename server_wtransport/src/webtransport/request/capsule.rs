@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+use quinn_proto::coding::Codec;
+use quinn_proto::{Connection, StreamId, VarInt};
+
+use crate::webtransport::{WebTransportError, frame, stream};
+
+// Capsule types (RFC 9297, Section 3.5).
+const CAPSULE_DATAGRAM: u64 = 0x00;
+
+/// The largest DATA frame or capsule we'll buffer from the peer.
+const MAX_CAPSULE_SIZE: usize = 16 * 1024;
+
+/// The most DATAGRAM capsules we'll queue in either direction before dropping new ones.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+/// Reads and writes capsules on the CONNECT stream once the session has been established.
+///
+/// Capsules are carried in DATA frames (RFC 9297, Section 3.2). We only understand DATAGRAM
+/// capsules, which stand in for QUIC datagrams when the path doesn't support them, and we skip
+/// everything else.
+pub struct CapsuleStream {
+    id: StreamId,
+
+    recv_buf: Vec<u8>,
+    capsule_buf: Vec<u8>,
+    datagrams: VecDeque<Bytes>,
+
+    send_buf: Vec<u8>,
+    send_queued: usize,
+}
+
+impl CapsuleStream {
+    /// `buffered` is anything already read from the stream, after the CONNECT headers.
+    pub fn new(id: StreamId, buffered: Vec<u8>) -> Self {
+        Self {
+            id,
+            recv_buf: buffered,
+            capsule_buf: Vec::new(),
+            datagrams: VecDeque::new(),
+            send_buf: Vec::new(),
+            send_queued: 0,
+        }
+    }
+
+    /// Takes the payload of the oldest DATAGRAM capsule we've received, if any.
+    pub fn recv_datagram(&mut self) -> Option<Bytes> {
+        self.datagrams.pop_front()
+    }
+
+    /// Queues a DATAGRAM capsule, which is written out on the next update.
+    ///
+    /// Like a QUIC datagram, this is unreliable: if the stream is backed up, it's dropped.
+    pub fn send_datagram(&mut self, payload: &[u8]) {
+        if self.send_queued >= MAX_QUEUED_DATAGRAMS {
+            return;
+        }
+
+        let mut capsule = Vec::with_capacity(payload.len() + 16);
+        frame::encode_varint(CAPSULE_DATAGRAM, &mut capsule);
+        frame::encode_varint(payload.len() as u64, &mut capsule);
+        capsule.extend_from_slice(payload);
+
+        frame::encode(frame::DATA, &capsule, &mut self.send_buf);
+        self.send_queued += 1;
+    }
+
+    pub fn update(&mut self, connection: &mut Connection) -> Result<(), WebTransportError> {
+        if self.send_buf.is_empty() == false {
+            let written = stream::write_some(connection, self.id, &self.send_buf)?;
+            self.send_buf.drain(..written);
+
+            if self.send_buf.is_empty() {
+                self.send_queued = 0;
+            }
+        }
+
+        loop {
+            let before = self.recv_buf.len();
+            let finished =
+                stream::read_to(connection, self.id, &mut self.recv_buf, MAX_CAPSULE_SIZE)?;
+            let read = self.recv_buf.len() - before;
+
+            self.handle_frames()?;
+            self.handle_capsules();
+
+            // Neither a frame nor a capsule is allowed to be bigger than our buffer
+            let buffered = self.recv_buf.len().max(self.capsule_buf.len());
+            if buffered >= MAX_CAPSULE_SIZE {
+                return Err(WebTransportError::ExcessiveLoad);
+            }
+
+            if finished {
+                // Finishing the CONNECT stream ends the session, and with it the connection
+                return Err(WebTransportError::SessionClosed);
+            }
+
+            if read == 0 {
+                return Ok(()); // Nothing new to read
+            }
+        }
+    }
+
+    /// Moves the payloads of any complete DATA frames into the capsule buffer.
+    fn handle_frames(&mut self) -> Result<(), WebTransportError> {
+        let mut offset = 0;
+        while let Some((frame, size)) = frame::decode(&self.recv_buf[offset..]) {
+            offset += size;
+
+            match frame.ty {
+                frame::DATA => self.capsule_buf.extend_from_slice(frame.payload),
+                // Trailers aren't meaningful here, and the rest are forbidden on request streams
+                frame::HEADERS | frame::PUSH_PROMISE => {
+                    return Err(WebTransportError::UnexpectedFrame(frame.ty));
+                }
+                ty if frame::is_control(ty) || frame::is_reserved_http2(ty) => {
+                    return Err(WebTransportError::UnexpectedFrame(ty));
+                }
+                _ => {} // Unknown frame types must be ignored
+            }
+        }
+
+        self.recv_buf.drain(..offset);
+        Ok(())
+    }
+
+    /// Decodes any complete capsules, keeping DATAGRAM payloads and skipping everything else.
+    fn handle_capsules(&mut self) {
+        let mut offset = 0;
+        while let Some((ty, payload, size)) = decode_capsule(&self.capsule_buf[offset..]) {
+            offset += size;
+
+            if ty == CAPSULE_DATAGRAM && self.datagrams.len() < MAX_QUEUED_DATAGRAMS {
+                self.datagrams.push_back(Bytes::copy_from_slice(payload));
+            }
+        }
+
+        self.capsule_buf.drain(..offset);
+    }
+}
+
+/// Decodes a single capsule from the front of `buf`, along with the number of bytes it occupies.
+///
+/// Returns `None` if `buf` does not yet contain the whole capsule.
+fn decode_capsule(buf: &[u8]) -> Option<(u64, &[u8], usize)> {
+    let mut cursor = buf;
+    let ty = VarInt::decode(&mut cursor).ok()?.into_inner();
+    let len = VarInt::decode(&mut cursor).ok()?.into_inner();
+
+    let len = usize::try_from(len).ok()?;
+    let payload = cursor.get(..len)?;
+    let size = (buf.len() - cursor.len()) + len;
+
+    Some((ty, payload, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::{Dir, Side};
+
+    use super::*;
+
+    #[test]
+    fn decodes_buffered_capsules() {
+        let mut capsule = Vec::new();
+        frame::encode_varint(CAPSULE_DATAGRAM, &mut capsule);
+        frame::encode_varint(5, &mut capsule);
+        capsule.extend_from_slice(b"hello");
+
+        let mut buffered = Vec::new();
+        frame::encode(frame::DATA, &capsule, &mut buffered);
+
+        let id = StreamId::new(Side::Client, Dir::Bi, 0);
+        let mut capsules = CapsuleStream::new(id, buffered);
+        capsules.handle_frames().unwrap();
+        capsules.handle_capsules();
+
+        assert_eq!(capsules.recv_datagram().as_deref(), Some(&b"hello"[..]));
+        assert!(capsules.recv_datagram().is_none());
+    }
+}
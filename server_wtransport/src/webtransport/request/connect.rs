@@ -1,51 +1,29 @@
-use std::io::Cursor;
-
-use quinn_proto::{Connection, Dir, StreamId};
+use quinn_proto::StreamId;
 use url::Url;
-use web_transport_proto::{ConnectError, ConnectRequest};
 
 use crate::webtransport::WebTransportError;
 
-#[derive(Default)]
+use super::streams::RequestStreams;
+
 pub struct Connect {
-    stream_id: Option<StreamId>,
+    draft_header: bool,
 }
 
-#[allow(dead_code)]
 impl Connect {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(draft_header: bool) -> Self {
+        Self { draft_header }
+    }
+
+    /// Whether the response needs the draft02 header, as negotiated from SETTINGS.
+    pub fn draft_header(&self) -> bool {
+        self.draft_header
     }
 
     pub fn update(
         &mut self,
-        connection: &mut Connection,
-        recv_buf: &mut Vec<u8>,
-    ) -> Result<Option<(Url, StreamId)>, WebTransportError> {
-        if self.stream_id.is_none() {
-            // There's no point at which we close this stream. If we error, we'll nuke the whole
-            // connection. If we succeed, the stream stays open for the whole connection lifetime.
-            self.stream_id = connection.streams().accept(Dir::Bi); // Bidirectional for response
-        }
-
-        if let Some(recv_id) = self.stream_id {
-            let mut recv_stream = connection.recv_stream(recv_id);
-            let recv_chunk = recv_stream
-                .read(true)
-                .map_err(|_| WebTransportError::UnexpectedEnd)?
-                .next(usize::MAX)?
-                .ok_or(WebTransportError::UnexpectedEnd)?;
-
-            recv_buf.extend_from_slice(&recv_chunk.bytes);
-
-            return match ConnectRequest::decode(&mut Cursor::new(&recv_buf)) {
-                Err(ConnectError::UnexpectedEnd) => Ok(None), // Keep trying
-                Err(e) => Err(e.into()),
-
-                Ok(connect) => Ok(Some((connect.url, recv_id))),
-            };
-        }
-
-        Ok(None) // Keep trying
+        streams: &mut RequestStreams,
+    ) -> Result<Option<(Url, StreamId, Vec<u8>)>, WebTransportError> {
+        // Anything that isn't a valid WebTransport CONNECT was already answered by the streams
+        Ok(streams.take_connect())
     }
 }
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+
+use quinn_proto::{Connection, StreamId};
+
+use crate::webtransport::settings::H3Settings;
+use crate::webtransport::{WebTransportError, frame, stream};
+
+/// Notable frames received on the peer's control stream after its SETTINGS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The peer is shutting down and won't accept pushes at or above this push ID.
+    GoAway(u64),
+    /// The peer raised the maximum push ID we're allowed to use.
+    MaxPushId(u64),
+}
+
+/// The peer's HTTP/3 control stream, which is read for the whole lifetime of the connection.
+pub struct ControlStream {
+    id: StreamId,
+    buf: Vec<u8>,
+    max_frame_size: usize,
+    settings: Option<H3Settings>,
+
+    goaway: Option<u64>,
+    max_push_id: Option<u64>,
+    events: VecDeque<SessionEvent>,
+}
+
+impl ControlStream {
+    pub fn new(id: StreamId, max_frame_size: usize) -> Self {
+        Self {
+            id,
+            buf: Vec::new(),
+            max_frame_size,
+            settings: None,
+            goaway: None,
+            max_push_id: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn settings(&self) -> Option<&H3Settings> {
+        self.settings.as_ref()
+    }
+
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn update(&mut self, connection: &mut Connection) -> Result<(), WebTransportError> {
+        loop {
+            // Never buffer more than one maximum-size frame's worth of data at a time
+            let before = self.buf.len();
+            let finished =
+                stream::read_to(connection, self.id, &mut self.buf, self.max_frame_size)?;
+            let read = self.buf.len() - before;
+
+            self.handle_frames()?;
+
+            // If we're still holding a full buffer, it's a single frame bigger than we allow
+            if self.buf.len() >= self.max_frame_size {
+                return Err(WebTransportError::ExcessiveLoad);
+            }
+
+            // The control stream must stay open for the lifetime of the connection
+            if finished {
+                return Err(WebTransportError::ClosedCriticalStream);
+            }
+
+            if read == 0 {
+                return Ok(()); // Nothing new to read
+            }
+        }
+    }
+
+    fn handle_frames(&mut self) -> Result<(), WebTransportError> {
+        let mut offset = 0;
+        while let Some((frame, size)) = frame::decode(&self.buf[offset..]) {
+            offset += size;
+
+            if self.settings.is_none() {
+                // The first frame must be SETTINGS, and it must never be sent again after that
+                match frame.ty {
+                    frame::SETTINGS => self.settings = Some(H3Settings::decode(frame.payload)?),
+                    _ => return Err(WebTransportError::MissingSettings),
+                }
+                continue;
+            }
+
+            match frame.ty {
+                frame::GOAWAY => {
+                    let id = frame::decode_varint_payload(frame.ty, frame.payload)?;
+
+                    // The peer can only ever lower the ID in subsequent GOAWAY frames
+                    if self.goaway.is_some_and(|previous| id > previous) {
+                        return Err(WebTransportError::InvalidFrameId(frame.ty, id));
+                    }
+
+                    self.goaway = Some(id);
+                    self.events.push_back(SessionEvent::GoAway(id));
+                }
+
+                frame::MAX_PUSH_ID => {
+                    let id = frame::decode_varint_payload(frame.ty, frame.payload)?;
+
+                    // The peer can never lower its maximum push ID
+                    if self.max_push_id.is_some_and(|previous| id < previous) {
+                        return Err(WebTransportError::InvalidFrameId(frame.ty, id));
+                    }
+
+                    self.max_push_id = Some(id);
+                    self.events.push_back(SessionEvent::MaxPushId(id));
+                }
+
+                frame::CANCEL_PUSH => {
+                    // We never push, so there's no push ID the peer could validly cancel
+                    let id = frame::decode_varint_payload(frame.ty, frame.payload)?;
+                    return Err(WebTransportError::InvalidFrameId(frame.ty, id));
+                }
+
+                frame::SETTINGS | frame::DATA | frame::HEADERS | frame::PUSH_PROMISE => {
+                    return Err(WebTransportError::UnexpectedFrame(frame.ty));
+                }
+
+                ty if frame::is_reserved_http2(ty) => {
+                    return Err(WebTransportError::UnexpectedFrame(ty));
+                }
+
+                _ => {} // Unknown frame types must be ignored
+            }
+        }
+
+        self.buf.drain(..offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::{Dir, Side, VarInt};
+
+    use super::*;
+    use crate::webtransport::WebTransportConfig;
+    use crate::webtransport::error::{
+        H3_EXCESSIVE_LOAD, H3_FRAME_UNEXPECTED, H3_ID_ERROR, H3_MISSING_SETTINGS,
+    };
+    use crate::webtransport::testing::{self, TestClient};
+
+    fn control_stream() -> ControlStream {
+        let id = StreamId::new(Side::Client, Dir::Uni, 0);
+        let mut control = ControlStream::new(id, 1024);
+        frame::encode(frame::SETTINGS, &[], &mut control.buf);
+        control
+    }
+
+    fn varint_frame(ty: u64, value: u64, buf: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        frame::encode_varint(value, &mut payload);
+        frame::encode(ty, &payload, buf);
+    }
+
+    fn error_code(control: &mut ControlStream) -> VarInt {
+        control.handle_frames().unwrap_err().code()
+    }
+
+    #[test]
+    fn settings_must_come_first() {
+        let id = StreamId::new(Side::Client, Dir::Uni, 0);
+        let mut control = ControlStream::new(id, 1024);
+        varint_frame(frame::GOAWAY, 0, &mut control.buf);
+
+        assert_eq!(error_code(&mut control), H3_MISSING_SETTINGS);
+    }
+
+    #[test]
+    fn rejects_a_second_settings() {
+        let mut control = control_stream();
+        frame::encode(frame::SETTINGS, &[], &mut control.buf);
+
+        assert_eq!(error_code(&mut control), H3_FRAME_UNEXPECTED);
+    }
+
+    #[test]
+    fn rejects_a_larger_goaway() {
+        let mut control = control_stream();
+        varint_frame(frame::GOAWAY, 8, &mut control.buf);
+        varint_frame(frame::GOAWAY, 4, &mut control.buf);
+        control.handle_frames().unwrap();
+        assert_eq!(control.poll_event(), Some(SessionEvent::GoAway(8)));
+        assert_eq!(control.poll_event(), Some(SessionEvent::GoAway(4)));
+
+        varint_frame(frame::GOAWAY, 12, &mut control.buf);
+        assert_eq!(error_code(&mut control), H3_ID_ERROR);
+    }
+
+    #[test]
+    fn rejects_a_decreasing_max_push_id() {
+        let mut control = control_stream();
+        varint_frame(frame::MAX_PUSH_ID, 4, &mut control.buf);
+        varint_frame(frame::MAX_PUSH_ID, 8, &mut control.buf);
+        control.handle_frames().unwrap();
+        assert_eq!(control.poll_event(), Some(SessionEvent::MaxPushId(4)));
+        assert_eq!(control.poll_event(), Some(SessionEvent::MaxPushId(8)));
+
+        varint_frame(frame::MAX_PUSH_ID, 2, &mut control.buf);
+        assert_eq!(error_code(&mut control), H3_ID_ERROR);
+    }
+
+    #[test]
+    fn rejects_data_frames() {
+        let mut control = control_stream();
+        frame::encode(frame::DATA, b"data", &mut control.buf);
+
+        assert_eq!(error_code(&mut control), H3_FRAME_UNEXPECTED);
+    }
+
+    #[test]
+    fn ignores_unknown_frames() {
+        let mut control = control_stream();
+        frame::encode(frame::grease(), b"grease", &mut control.buf);
+
+        control.handle_frames().unwrap();
+        assert!(control.buf.is_empty());
+        assert_eq!(control.poll_event(), None);
+    }
+
+    #[test]
+    fn oversized_frames_are_excessive_load() {
+        let mut config = WebTransportConfig::default();
+        config.max_control_frame_size = 64;
+        let mut client = TestClient::connect(config);
+
+        let mut data = testing::client_settings();
+        frame::encode(frame::grease(), &[0; 100], &mut data);
+        client.open_uni(&data);
+        client.drive();
+
+        assert_eq!(client.close_code(), Some(H3_EXCESSIVE_LOAD));
+    }
+
+    #[test]
+    fn oversized_settings_are_excessive_load() {
+        let mut config = WebTransportConfig::default();
+        config.max_control_frame_size = 64;
+        let mut client = TestClient::connect(config);
+
+        let mut settings = H3Settings::default();
+        for id in 0..32 {
+            settings.insert(0x100 + id, 1);
+        }
+        let mut data = Vec::new();
+        settings.encode(&mut data);
+        client.open_uni(&data);
+        client.drive();
+
+        assert_eq!(client.close_code(), Some(H3_EXCESSIVE_LOAD));
+    }
+}
@@ -1,17 +1,31 @@
+mod buffered;
+mod capsule;
 mod connect;
+mod control;
 mod response;
 mod settings;
+mod streams;
+mod uni;
 
+use std::sync::Arc;
+
+use bytes::Bytes;
 use http::StatusCode;
 use quinn_proto::coding::Codec;
 use quinn_proto::{Connection, StreamId, VarInt};
 use url::Url;
 
-use crate::webtransport::WebTransportError;
+use crate::webtransport::{WebTransportConfig, WebTransportError};
 
+use capsule::CapsuleStream;
 use connect::Connect;
 use response::Response;
 use settings::Settings;
+use streams::RequestStreams;
+use uni::UniStreams;
+
+pub use control::SessionEvent;
+pub use settings::Capabilities;
 
 const DATA_BUFFER_SIZE: usize = 128;
 
@@ -24,7 +38,11 @@ pub enum RequestState {
 
 pub struct Request {
     data_buf: Vec<u8>,
+    capabilities: Option<Capabilities>,
+    capsule_fallback: bool,
     inner: RequestInner,
+    streams: RequestStreams,
+    uni: UniStreams,
 }
 
 enum RequestInner {
@@ -36,14 +54,21 @@ enum RequestInner {
 
 pub struct Completed {
     pub session_id: VarInt,
+    /// The session ID divided by four, which prefixes every HTTP datagram (RFC 9297).
+    pub quarter_stream_id: VarInt,
     pub datagram_header: Box<[u8]>,
+    capsules: CapsuleStream,
 }
 
 impl Request {
-    pub fn new() -> Self {
+    pub fn new(config: Arc<WebTransportConfig>) -> Self {
         Self {
             data_buf: Vec::with_capacity(DATA_BUFFER_SIZE),
-            inner: RequestInner::Settings(Settings::new()),
+            capabilities: None,
+            capsule_fallback: config.datagram_capsule_fallback,
+            inner: RequestInner::Settings(Settings::new(&config)),
+            uni: UniStreams::new(config.max_control_frame_size),
+            streams: RequestStreams::new(config),
         }
     }
 
@@ -54,6 +79,50 @@ impl Request {
         }
     }
 
+    /// What the peer supports, once its SETTINGS have been received and validated.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Whether datagrams should be sent as capsules when QUIC datagrams are unavailable.
+    pub fn capsule_fallback(&self) -> bool {
+        self.capsule_fallback
+    }
+
+    /// Takes the payload of the oldest DATAGRAM capsule received on the CONNECT stream, if any.
+    pub fn recv_capsule_datagram(&mut self) -> Option<Bytes> {
+        match self.inner {
+            RequestInner::Completed(ref mut completed) => completed.capsules.recv_datagram(),
+            _ => None,
+        }
+    }
+
+    /// Queues a DATAGRAM capsule on the CONNECT stream, which is sent on the next update.
+    pub fn send_capsule_datagram(&mut self, payload: &[u8]) -> Result<(), WebTransportError> {
+        match self.inner {
+            RequestInner::Completed(ref mut completed) => completed.capsules.send_datagram(payload),
+            _ => Err(WebTransportError::WebTransportNotConnected)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.uni.poll_event()
+    }
+
+    /// Takes the oldest unidirectional stream the peer opened for our WebTransport session.
+    pub fn accept_uni(&mut self) -> Option<StreamId> {
+        let session_id = self.completed()?.session_id;
+        self.uni.accept_webtransport(session_id)
+    }
+
+    /// Takes the oldest bidirectional stream the peer opened for our WebTransport session.
+    pub fn accept_bi(&mut self) -> Option<StreamId> {
+        let session_id = self.completed()?.session_id;
+        self.streams.accept_webtransport(session_id)
+    }
+
     pub fn respond(&mut self, status: StatusCode) -> Result<(), WebTransportError> {
         match &mut self.inner {
             RequestInner::Response(r) => r.start_response(&mut self.data_buf, status),
@@ -67,20 +136,39 @@ impl Request {
         &mut self,
         connection: &mut Connection,
     ) -> Result<RequestState, WebTransportError> {
-        if let RequestInner::Completed(_) = self.inner {
+        // Simple requests (e.g. GET) can arrive at any point, so always handle those first
+        self.streams.update(connection)?;
+        self.uni.update(connection)?;
+
+        // Once we've taken a CONNECT, there's no room for another session
+        if matches!(
+            self.inner,
+            RequestInner::Response(_) | RequestInner::Completed(_)
+        ) {
+            self.streams.reject_connects(connection);
+        }
+
+        if let RequestInner::Completed(ref mut completed) = self.inner {
+            self.streams
+                .reject_other_sessions(connection, completed.session_id);
+            self.uni
+                .reject_other_sessions(connection, completed.session_id);
+            completed.capsules.update(connection)?;
             return Ok(RequestState::Completed);
         }
 
         if let RequestInner::Settings(ref mut state) = self.inner {
-            if state.update(connection, &mut self.data_buf)? {
-                self.inner = RequestInner::Connect(Connect::new());
+            if let Some(capabilities) = state.update(connection, &self.uni)? {
+                self.capabilities = Some(capabilities);
+                self.inner = RequestInner::Connect(Connect::new(capabilities.draft02));
                 self.data_buf.clear();
             }
         }
 
         if let RequestInner::Connect(ref mut state) = self.inner {
-            if let Some((url, connection_id)) = state.update(connection, &mut self.data_buf)? {
-                self.inner = RequestInner::Response(Response::new(connection_id));
+            if let Some((url, connection_id, buffered)) = state.update(&mut self.streams)? {
+                let response = Response::new(connection_id, state.draft_header(), buffered);
+                self.inner = RequestInner::Response(response);
                 self.data_buf.clear();
                 return Ok(RequestState::ConnectData(url));
             }
@@ -88,7 +176,8 @@ impl Request {
 
         if let RequestInner::Response(ref mut state) = self.inner {
             if let Some(session_id) = state.update(connection, &self.data_buf)? {
-                self.inner = RequestInner::Completed(Completed::new(session_id));
+                let completed = Completed::new(session_id, state.take_buffered());
+                self.inner = RequestInner::Completed(completed);
                 self.data_buf.clear();
                 return Ok(RequestState::ResponseSent(session_id));
             }
@@ -99,15 +188,40 @@ impl Request {
 }
 
 impl Completed {
-    fn new(session_id: StreamId) -> Self {
-        let mut header = Vec::with_capacity(1);
-        let session_id = VarInt::from(session_id);
-        session_id.encode(&mut header);
-        debug_assert_eq!(header.len(), 1);
+    fn new(id: StreamId, buffered: Vec<u8>) -> Self {
+        // Only datagrams use the quarter stream ID, streams are tagged with the full one
+        let session_id = VarInt::from(id);
+        let quarter_stream_id =
+            VarInt::from_u64(session_id.into_inner() / 4).expect("quarter of a varint is a varint");
+
+        let mut header = Vec::new();
+        quarter_stream_id.encode(&mut header);
 
         Self {
             session_id,
+            quarter_stream_id,
             datagram_header: header.into_boxed_slice(),
+            capsules: CapsuleStream::new(id, buffered),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::{Dir, Side};
+
+    use super::*;
+
+    #[test]
+    fn datagram_header_is_the_quarter_stream_id() {
+        let id = StreamId::new(Side::Client, Dir::Bi, 100);
+        let completed = Completed::new(id, Vec::new());
+        assert_eq!(completed.session_id.into_inner(), 400);
+        assert_eq!(completed.quarter_stream_id.into_inner(), 100);
+
+        // Ids past 63 no longer fit a single byte varint
+        let header = VarInt::decode(&mut &completed.datagram_header[..]).unwrap();
+        assert_eq!(header.into_inner(), 100);
+        assert_eq!(completed.datagram_header.len(), 2);
+    }
+}
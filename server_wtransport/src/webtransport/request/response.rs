@@ -1,25 +1,48 @@
 use http::StatusCode;
 use quinn_proto::{Connection, StreamId};
-use web_transport_proto::ConnectResponse;
 
-use crate::webtransport::WebTransportError;
+use crate::webtransport::{WebTransportError, frame, qpack, stream};
 
 pub struct Response {
     send_id: StreamId,
     send_bytes: usize,
+    draft_header: bool,
+    buffered: Vec<u8>,
 }
 
 impl Response {
-    pub fn new(send_id: StreamId) -> Response {
+    /// `buffered` is anything the client sent after the CONNECT headers, which is held on to
+    /// until the session is established.
+    ///
+    /// `draft_header` adds the header that clients which enabled draft02 refuse the session
+    /// without.
+    pub fn new(send_id: StreamId, draft_header: bool, buffered: Vec<u8>) -> Response {
         Self {
             send_id,
             send_bytes: 0,
+            draft_header,
+            buffered,
         }
     }
 
+    /// Takes what the client sent after the CONNECT headers, to seed the capsule stream with.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffered)
+    }
+
     pub fn start_response(&mut self, data_buf: &mut Vec<u8>, status: StatusCode) {
         debug_assert!(data_buf.is_empty());
-        ConnectResponse { status }.encode(data_buf);
+
+        // A successful CONNECT response must not carry content-length, so this doesn't go
+        // through HttpResponse
+        let mut fields = vec![(":status", status.as_str())];
+        if self.draft_header {
+            fields.push(("sec-webtransport-http3-draft", "draft02"));
+        }
+
+        let mut headers = Vec::new();
+        qpack::encode(fields, &mut headers);
+        frame::encode(frame::HEADERS, &headers, data_buf);
     }
 
     pub fn update(
@@ -28,8 +51,8 @@ impl Response {
         data_response: &[u8],
     ) -> Result<Option<StreamId>, WebTransportError> {
         if data_response.is_empty() == false {
-            let mut send_stream = connection.send_stream(self.send_id);
-            self.send_bytes += send_stream.write(&data_response[self.send_bytes..])?;
+            let remaining = &data_response[self.send_bytes..];
+            self.send_bytes += stream::write_some(connection, self.send_id, remaining)?;
 
             if self.send_bytes >= data_response.len() {
                 return Ok(Some(self.send_id));
@@ -39,3 +62,96 @@ impl Response {
         Ok(None) // Keep trying
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::{Dir, Side};
+
+    use super::*;
+    use crate::webtransport::settings::{self, H3Settings};
+    use crate::webtransport::testing::TestClient;
+    use crate::webtransport::{WebTransportConfig, WebTransportDraft};
+
+    /// The draft header on the CONNECT response, for a client that sent the given settings.
+    fn draft_header(
+        client_settings: &[(u64, u64)],
+        drafts: &[WebTransportDraft],
+    ) -> Option<String> {
+        let mut config = WebTransportConfig::default();
+        config.webtransport_drafts = drafts.to_vec();
+        let mut client = TestClient::connect(config);
+
+        let mut settings = H3Settings::default();
+        settings.insert(settings::ENABLE_CONNECT_PROTOCOL, 1);
+        settings.insert(settings::H3_DATAGRAM, 1);
+        for &(id, value) in client_settings {
+            settings.insert(id, value);
+        }
+
+        let mut control = Vec::new();
+        settings.encode(&mut control);
+        client.open_uni(&control);
+        let connect = client.send_connect();
+        client.drive();
+        assert!(client.session().request.completed().is_some());
+
+        let response = client.read(connect);
+        let (frame, _) = frame::decode(&response).unwrap();
+        let fields = qpack::decode(frame.payload).unwrap();
+        assert!(fields.contains(&(":status".into(), "200".into())));
+
+        fields
+            .into_iter()
+            .find(|(name, _)| name == "sec-webtransport-http3-draft")
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn draft_header_only_for_draft02() {
+        let all = WebTransportDraft::ALL;
+        let draft02 = (settings::WEBTRANSPORT_ENABLE_DEPRECATED, 1);
+        let draft07 = (settings::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED, 1);
+        let latest = (settings::WEBTRANSPORT_MAX_SESSIONS, 1);
+
+        assert_eq!(draft_header(&[draft02], &all).as_deref(), Some("draft02"));
+        assert_eq!(draft_header(&[draft07], &all), None);
+        assert_eq!(draft_header(&[latest], &all), None);
+    }
+
+    #[test]
+    fn draft_header_for_clients_that_also_enable_draft02() {
+        // These clients negotiate a newer draft, but may still insist on the header
+        let all = WebTransportDraft::ALL;
+        let draft02 = (settings::WEBTRANSPORT_ENABLE_DEPRECATED, 1);
+        let draft07 = (settings::WEBTRANSPORT_MAX_SESSIONS_DEPRECATED, 1);
+        let latest = (settings::WEBTRANSPORT_MAX_SESSIONS, 1);
+
+        let header = draft_header(&[draft02, draft07], &all);
+        assert_eq!(header.as_deref(), Some("draft02"));
+        let header = draft_header(&[draft02, draft07, latest], &all);
+        assert_eq!(header.as_deref(), Some("draft02"));
+
+        // Unless we don't offer draft02 at all
+        let drafts = [WebTransportDraft::Draft07, WebTransportDraft::Latest];
+        assert_eq!(draft_header(&[draft02, draft07], &drafts), None);
+    }
+
+    #[test]
+    fn encodes_the_draft_header() {
+        for draft_header in [false, true] {
+            let id = StreamId::new(Side::Client, Dir::Bi, 0);
+            let mut response = Response::new(id, draft_header, Vec::new());
+
+            let mut data = Vec::new();
+            response.start_response(&mut data, StatusCode::OK);
+
+            let (frame, size) = frame::decode(&data).unwrap();
+            assert_eq!(size, data.len());
+            let fields = qpack::decode(frame.payload).unwrap();
+            let found = fields
+                .iter()
+                .any(|(name, value)| name == "sec-webtransport-http3-draft" && value == "draft02");
+            assert_eq!(found, draft_header);
+        }
+    }
+}
@@ -1,45 +1,87 @@
-use std::io::Cursor;
-use std::sync::LazyLock;
-
 use quinn_proto::{Connection, Dir, StreamId};
-use web_transport_proto::{Settings as SettingsData, SettingsError};
 
-use crate::webtransport::WebTransportError;
+use crate::webtransport::error::H3_NO_ERROR;
+use crate::webtransport::{
+    WebTransportConfig, WebTransportDraft, WebTransportError, frame, stream,
+};
+
+use super::uni::UniStreams;
 
-// This never changes at runtime, but is nondeterministic due to being HashMap-based.
-static SETTINGS_ENCODED: LazyLock<&[u8]> = LazyLock::new(encode_settings);
+/// What the peer supports, as established by its SETTINGS and the QUIC handshake.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    /// Whether the peer sent SETTINGS_ENABLE_CONNECT_PROTOCOL.
+    pub extended_connect: bool,
+    /// Whether the peer sent SETTINGS_H3_DATAGRAM.
+    pub h3_datagram: bool,
+    /// The largest QUIC datagram the peer accepts, or `None` if it doesn't support them.
+    pub max_datagram_size: Option<usize>,
+    /// The WebTransport draft this session speaks.
+    pub draft: WebTransportDraft,
+    /// Whether draft02 was enabled by both of us, even if a newer draft was negotiated.
+    ///
+    /// Clients that enable it expect the draft02 header on the CONNECT response either way.
+    pub draft02: bool,
+    /// The number of concurrent WebTransport sessions the peer supports under that draft.
+    pub webtransport_max_sessions: u64,
+}
 
-#[derive(Default)]
 pub struct Settings {
     send_done: bool,
-    recv_done: bool,
+    recv: Option<Capabilities>,
+    require_datagrams: bool,
+    capsule_fallback: bool,
+    drafts: Vec<WebTransportDraft>,
 
     send_id: Option<StreamId>,
-    recv_id: Option<StreamId>,
+    grease: bool,
 
+    encoded: Vec<u8>,
     send_bytes: usize,
 }
 
-#[allow(dead_code)]
 impl Settings {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(config: &WebTransportConfig) -> Self {
+        let mut encoded = Vec::new();
+        config.h3_settings().encode(&mut encoded);
+
+        if config.grease {
+            // A reserved frame after SETTINGS, which the peer must ignore
+            frame::encode(frame::grease(), b"grease", &mut encoded);
+        }
+
+        Self {
+            send_done: false,
+            recv: None,
+            require_datagrams: config.enable_datagram,
+            capsule_fallback: config.datagram_capsule_fallback,
+            drafts: config.webtransport_drafts.clone(),
+            send_id: None,
+            grease: config.grease,
+            encoded,
+            send_bytes: 0,
+        }
     }
 
+    /// Returns the peer's capabilities once SETTINGS have been both sent and received.
     pub fn update(
         &mut self,
         connection: &mut Connection,
-        recv_buf: &mut Vec<u8>,
-    ) -> Result<bool, WebTransportError> {
+        uni: &UniStreams,
+    ) -> Result<Option<Capabilities>, WebTransportError> {
         if self.send_done == false {
             self.send_done |= self.try_send(connection)?;
         }
 
-        if self.recv_done == false {
-            self.recv_done |= self.try_recv(connection, recv_buf)?;
+        if self.recv.is_none() {
+            self.recv = self.try_recv(connection, uni)?;
         }
 
-        Ok(self.send_done && self.recv_done)
+        match self.send_done {
+            true => Ok(self.recv),
+            false => Ok(None), // Keep trying
+        }
     }
 
     fn try_send(&mut self, connection: &mut Connection) -> Result<bool, WebTransportError> {
@@ -49,13 +91,17 @@ impl Settings {
             // There's no point at which we close this stream. If we error, we'll nuke the whole
             // connection. If we succeed, the stream stays open for the whole connection lifetime.
             self.send_id = connection.streams().open(Dir::Uni);
+
+            if self.send_id.is_some() && self.grease {
+                send_grease_stream(connection);
+            }
         }
 
         if let Some(send_id) = self.send_id {
-            let mut send_stream = connection.send_stream(send_id);
-            self.send_bytes += send_stream.write(&SETTINGS_ENCODED[self.send_bytes..])?;
+            let remaining = &self.encoded[self.send_bytes..];
+            self.send_bytes += stream::write_some(connection, send_id, remaining)?;
 
-            if self.send_bytes >= SETTINGS_ENCODED.len() {
+            if self.send_bytes >= self.encoded.len() {
                 return Ok(true);
             }
         }
@@ -66,47 +112,203 @@ impl Settings {
     fn try_recv(
         &mut self,
         connection: &mut Connection,
-        recv_buf: &mut Vec<u8>,
-    ) -> Result<bool, WebTransportError> {
-        debug_assert!(self.recv_done == false);
+        uni: &UniStreams,
+    ) -> Result<Option<Capabilities>, WebTransportError> {
+        debug_assert!(self.recv.is_none());
 
-        if self.recv_id.is_none() {
-            // There's no point at which we close this stream. If we error, we'll nuke the whole
-            // connection. If we succeed, the stream stays open for the whole connection lifetime.
-            self.recv_id = connection.streams().accept(Dir::Uni);
+        // The peer's control stream may arrive at any point, and in any order relative to its
+        // other unidirectional streams, so we just wait for the dispatcher to find its SETTINGS
+        let Some(settings) = uni.peer_settings() else {
+            return Ok(None); // Keep trying
+        };
+
+        if settings.supports_extended_connect() == false {
+            return Err(WebTransportError::ExtendedConnectUnsupported);
         }
 
-        if let Some(recv_id) = self.recv_id {
-            let mut recv_stream = connection.recv_stream(recv_id);
-            let recv_chunk = recv_stream
-                .read(true)
-                .map_err(|_| WebTransportError::UnexpectedEnd)?
-                .next(usize::MAX)?
-                .ok_or(WebTransportError::UnexpectedEnd)?;
-
-            recv_buf.extend_from_slice(&recv_chunk.bytes);
-
-            return match SettingsData::decode(&mut Cursor::new(&recv_buf)) {
-                Err(SettingsError::UnexpectedEnd) => Ok(false), // Keep trying
-                Err(e) => Err(e.into()),
-
-                Ok(settings) => match settings.supports_webtransport() {
-                    0 => Err(WebTransportError::WebTransportUnsupported),
-                    _ => Ok(true), // We're done!
-                },
-            };
+        let Some(draft) = settings.negotiate_draft(&self.drafts) else {
+            return Err(WebTransportError::WebTransportUnsupported);
+        };
+
+        let capabilities = Capabilities {
+            extended_connect: true,
+            h3_datagram: settings.supports_h3_datagram(),
+            max_datagram_size: connection.datagrams().max_size(),
+            draft,
+            draft02: self.drafts.contains(&WebTransportDraft::Draft02)
+                && settings.webtransport_max_sessions(WebTransportDraft::Draft02) > 0,
+            webtransport_max_sessions: settings.webtransport_max_sessions(draft),
+        };
+
+        // HTTP datagrams are carried in QUIC datagrams, so advertising one without the other is a
+        // settings error (RFC 9297, Section 2.1.1), even if we'd never use either
+        if capabilities.h3_datagram && capabilities.max_datagram_size.is_none() {
+            return Err(WebTransportError::QuicDatagramUnsupported);
         }
 
-        Ok(false) // Keep trying
+        // Peers without HTTP datagrams can still get them as capsules, if we're allowed to
+        let fallback = self.capsule_fallback;
+        if self.require_datagrams && capabilities.h3_datagram == false && fallback == false {
+            return Err(WebTransportError::H3DatagramUnsupported);
+        }
+
+        Ok(Some(capabilities))
     }
 }
 
-fn encode_settings() -> &'static [u8] {
-    let mut settings = SettingsData::default();
-    settings.enable_webtransport(1);
+/// A reserved stream type followed by some junk for the peer to ignore.
+fn grease_stream_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    frame::encode_varint(frame::grease(), &mut data);
+    data.extend_from_slice(b"grease");
+    data
+}
+
+/// Opens a unidirectional stream with a reserved type, which the peer must ignore (or stop).
+///
+/// This is best-effort, so if we can't open the stream or write it in one go, we give up on it.
+fn send_grease_stream(connection: &mut Connection) {
+    let Some(id) = connection.streams().open(Dir::Uni) else {
+        return;
+    };
+
+    let data = grease_stream_data();
+    match stream::write_some(connection, id, &data) {
+        Ok(written) if written == data.len() => _ = connection.send_stream(id).finish(),
+        _ => _ = connection.send_stream(id).reset(H3_NO_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::coding::Codec;
+    use quinn_proto::{Side, TransportConfig, VarInt};
+
+    use super::*;
+    use crate::webtransport::error::H3_SETTINGS_ERROR;
+    use crate::webtransport::settings::{self, H3Settings};
+    use crate::webtransport::testing::{self, TestClient};
+
+    /// The type of every frame on the control stream, after the stream type.
+    fn frame_types(encoded: &[u8]) -> Vec<u64> {
+        let mut buf = &encoded[1..];
+        let mut types = Vec::new();
+        while let Some((frame, size)) = frame::decode(buf) {
+            types.push(frame.ty);
+            buf = &buf[size..];
+        }
+
+        assert!(buf.is_empty());
+        types
+    }
+
+    #[test]
+    fn grease_adds_a_reserved_frame() {
+        let mut config = WebTransportConfig::default();
+        config.grease = true;
+
+        let types = frame_types(&Settings::new(&config).encoded);
+        assert_eq!(types.len(), 2);
+        assert_eq!(types[0], frame::SETTINGS);
+        assert!(frame::is_grease(types[1]));
+    }
+
+    #[test]
+    fn no_reserved_frames_without_grease() {
+        let types = frame_types(&Settings::new(&WebTransportConfig::default()).encoded);
+        assert_eq!(types, [frame::SETTINGS]);
+    }
+
+    #[test]
+    fn grease_streams_have_a_reserved_type() {
+        let data = grease_stream_data();
+        let ty = VarInt::decode(&mut &data[..]).unwrap().into_inner();
+        assert!(frame::is_grease(ty));
+    }
+
+    /// A client control stream, with or without SETTINGS_H3_DATAGRAM.
+    fn client_settings(h3_datagram: bool) -> Vec<u8> {
+        let mut settings = H3Settings::default();
+        settings.insert(settings::ENABLE_CONNECT_PROTOCOL, 1);
+        settings.insert(settings::WEBTRANSPORT_MAX_SESSIONS, 1);
+        if h3_datagram {
+            settings.insert(settings::H3_DATAGRAM, 1);
+        }
+
+        let mut data = Vec::new();
+        settings.encode(&mut data);
+        data
+    }
+
+    /// Connects a client, which may not support QUIC datagrams, and sends a CONNECT.
+    fn connect(config: WebTransportConfig, h3_datagram: bool, quic_datagram: bool) -> TestClient {
+        let mut transport = TransportConfig::default();
+        if quic_datagram == false {
+            transport.datagram_receive_buffer_size(None);
+        }
+
+        let mut client = TestClient::connect_with_transport(config, transport);
+        client.open_uni(&client_settings(h3_datagram));
+        client.send_connect();
+        client.drive();
+        client
+    }
+
+    fn fallback_config() -> WebTransportConfig {
+        let mut config = WebTransportConfig::default();
+        config.datagram_capsule_fallback = true;
+        config
+    }
+
+    /// Sends a datagram from the server, and checks it arrived as a capsule on the CONNECT stream.
+    fn assert_sent_as_capsule(client: &mut TestClient) {
+        let capabilities = client.session().capabilities().copied().unwrap();
+        assert!(capabilities.h3_datagram == false || capabilities.max_datagram_size.is_none());
 
-    let mut buf = Vec::<u8>::new();
-    settings.encode(&mut buf);
+        let connect = StreamId::new(Side::Client, Dir::Bi, 0);
+        let before = client.read(connect);
+        assert_eq!(testing::response_status(&before), Some(200));
 
-    Box::leak(buf.into_boxed_slice())
+        client
+            .session()
+            .send_datagram(|buf| {
+                buf[..5].copy_from_slice(b"hello");
+                5
+            })
+            .unwrap();
+        client.drive();
+
+        assert!(client.connection.datagrams().recv().is_none());
+        assert!(client.read(connect).ends_with(b"hello"));
+    }
+
+    #[test]
+    fn fallback_accepts_peers_without_h3_datagram() {
+        let mut client = connect(fallback_config(), false, true);
+        assert_eq!(client.close_code(), None);
+        assert!(client.session().request.completed().is_some());
+        assert_sent_as_capsule(&mut client);
+    }
+
+    #[test]
+    fn fallback_accepts_peers_without_any_datagrams() {
+        let mut client = connect(fallback_config(), false, false);
+        assert_eq!(client.close_code(), None);
+        assert!(client.session().request.completed().is_some());
+        assert_sent_as_capsule(&mut client);
+    }
+
+    #[test]
+    fn h3_datagram_without_quic_datagrams_is_a_settings_error() {
+        for config in [WebTransportConfig::default(), fallback_config()] {
+            let mut client = connect(config, true, false);
+            assert_eq!(client.close_code(), Some(H3_SETTINGS_ERROR));
+        }
+    }
+
+    #[test]
+    fn peers_without_h3_datagram_need_the_fallback() {
+        let mut client = connect(WebTransportConfig::default(), false, true);
+        assert_eq!(client.close_code(), Some(H3_SETTINGS_ERROR));
+    }
 }
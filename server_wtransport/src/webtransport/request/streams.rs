@@ -0,0 +1,437 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use http::{Method, StatusCode};
+use quinn_proto::{Connection, Dir, StreamId, VarInt};
+use url::Url;
+
+use crate::webtransport::error::{H3_NO_ERROR, H3_REQUEST_CANCELLED, H3_REQUEST_INCOMPLETE};
+use crate::webtransport::frame::MAX_FRAME_HEADER_SIZE;
+use crate::webtransport::stream::{self, PendingWrite};
+use crate::webtransport::{
+    HttpRequest, HttpResponse, WebTransportConfig, WebTransportError, frame,
+};
+
+use super::buffered::BufferedStreams;
+
+/// Accepts HTTP/3 request streams, answering simple requests and queueing WebTransport CONNECTs.
+///
+/// GET and HEAD requests are answered by the configured routes (or with 404), other methods get a
+/// 405, and CONNECT requests that aren't valid WebTransport requests get a 400.
+///
+/// WebTransport bidirectional streams share the same stream space, and are told apart by their
+/// first varint. These are held for the session instead.
+pub struct RequestStreams {
+    config: Arc<WebTransportConfig>,
+
+    incoming: Vec<IncomingRequest>,
+    connects: VecDeque<(Url, StreamId, Vec<u8>)>, // Along with anything sent after the headers
+    responses: Vec<PendingWrite>,
+    webtransport: BufferedStreams,
+}
+
+struct IncomingRequest {
+    id: StreamId,
+    ty: Option<u64>, // The first varint, which is a frame type unless this is a WebTransport stream
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+enum Incoming {
+    Request(HttpRequest),
+    WebTransport(VarInt),
+}
+
+impl RequestStreams {
+    pub fn new(config: Arc<WebTransportConfig>) -> Self {
+        Self {
+            config,
+            incoming: Vec::new(),
+            connects: VecDeque::new(),
+            responses: Vec::new(),
+            webtransport: BufferedStreams::new(),
+        }
+    }
+
+    /// Takes the oldest WebTransport bidirectional stream for the given session, if any.
+    ///
+    /// The stream's header has already been read, so everything left on it is application data.
+    pub fn accept_webtransport(&mut self, session_id: VarInt) -> Option<StreamId> {
+        self.webtransport.accept(session_id)
+    }
+
+    /// Rejects WebTransport streams for any session but ours, now that we know which that is.
+    pub fn reject_other_sessions(&mut self, connection: &mut Connection, session_id: VarInt) {
+        self.webtransport.reject_others(connection, session_id);
+    }
+
+    /// Takes the oldest WebTransport CONNECT request that hasn't been handled yet, if any.
+    ///
+    /// Along with the URL and stream, this returns whatever the client sent after the headers,
+    /// which is the start of the session's capsules.
+    pub fn take_connect(&mut self) -> Option<(Url, StreamId, Vec<u8>)> {
+        self.connects.pop_front()
+    }
+
+    /// Turns away every WebTransport CONNECT that hasn't been handled yet with a 429.
+    ///
+    /// We only support a single session per connection, so this is for any beyond the first.
+    pub fn reject_connects(&mut self, connection: &mut Connection) {
+        while let Some((_, id, _)) = self.connects.pop_front() {
+            let response = HttpResponse::new(StatusCode::TOO_MANY_REQUESTS);
+            self.respond(connection, id, &Method::CONNECT, response);
+        }
+    }
+
+    pub fn update(&mut self, connection: &mut Connection) -> Result<(), WebTransportError> {
+        while let Some(id) = connection.streams().accept(Dir::Bi) {
+            self.incoming
+                .push(IncomingRequest::new(id, self.config.max_header_size));
+        }
+
+        let mut index = 0;
+        while index < self.incoming.len() {
+            match self.incoming[index].update(connection) {
+                Ok(Some(Incoming::Request(request))) => {
+                    let incoming = self.incoming.swap_remove(index);
+                    self.dispatch(connection, request, incoming.id, incoming.buf);
+                }
+                Ok(Some(Incoming::WebTransport(session_id))) => {
+                    let id = self.incoming.swap_remove(index).id;
+                    self.webtransport.push(connection, session_id, id);
+                }
+                Ok(None) => index += 1,
+
+                Err(WebTransportError::MalformedRequest) => {
+                    let id = self.incoming.swap_remove(index).id;
+                    let response = HttpResponse::new(StatusCode::BAD_REQUEST);
+                    self.respond(connection, id, &Method::GET, response);
+                }
+                Err(WebTransportError::HeadersTooLarge) => {
+                    let id = self.incoming.swap_remove(index).id;
+                    let response = HttpResponse::new(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                    self.respond(connection, id, &Method::GET, response);
+                }
+
+                // The client abandoned the request, which only affects its own stream
+                Err(WebTransportError::UnexpectedEnd) => {
+                    let id = self.incoming.swap_remove(index).id;
+                    _ = connection.send_stream(id).reset(H3_REQUEST_INCOMPLETE);
+                }
+                Err(WebTransportError::ReadError(_)) => {
+                    let id = self.incoming.swap_remove(index).id;
+                    _ = connection.send_stream(id).reset(H3_REQUEST_CANCELLED);
+                }
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut index = 0;
+        while index < self.responses.len() {
+            match self.responses[index].update(connection) {
+                Ok(false) => index += 1,
+                Ok(true) => {
+                    self.responses.swap_remove(index);
+                }
+
+                // The client stopped reading the response, which only affects its own stream
+                Err(WebTransportError::WriteError(_)) => {
+                    self.responses.swap_remove(index);
+                }
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(
+        &mut self,
+        connection: &mut Connection,
+        request: HttpRequest,
+        id: StreamId,
+        buffered: Vec<u8>,
+    ) {
+        let response = match request.method {
+            Method::CONNECT => match request.webtransport_url() {
+                Some(url) => {
+                    // There's no point at which we close this stream. If we error, we'll nuke the
+                    // whole connection. If we succeed, the stream stays open for its lifetime.
+                    self.connects.push_back((url, id, buffered));
+                    return;
+                }
+                None => HttpResponse::new(StatusCode::BAD_REQUEST),
+            },
+
+            Method::GET | Method::HEAD => {
+                // Routes only match on the path, so leave off any query string
+                let path = request.path.as_deref().and_then(|p| p.split('?').next());
+                let handler = path.and_then(|p| self.config.route(p));
+                match handler {
+                    Some(handler) => handler(&request),
+                    None => HttpResponse::new(StatusCode::NOT_FOUND),
+                }
+            }
+
+            _ => {
+                HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED).with_header("allow", "GET, HEAD")
+            }
+        };
+
+        self.respond(connection, id, &request.method, response);
+    }
+
+    fn respond(
+        &mut self,
+        connection: &mut Connection,
+        id: StreamId,
+        method: &Method,
+        response: HttpResponse,
+    ) {
+        let mut data = Vec::new();
+        response.encode(method, &mut data);
+
+        // We've answered, so we don't care about anything else the client sends on this stream
+        _ = connection.recv_stream(id).stop(H3_NO_ERROR);
+        self.responses.push(PendingWrite::new(id, data));
+    }
+}
+
+impl IncomingRequest {
+    fn new(id: StreamId, max_size: usize) -> Self {
+        Self {
+            id,
+            ty: None,
+            buf: Vec::new(),
+            max_size,
+        }
+    }
+
+    fn update(
+        &mut self,
+        connection: &mut Connection,
+    ) -> Result<Option<Incoming>, WebTransportError> {
+        // Only read the first varint, so nothing after a WebTransport header is taken from the app
+        let ty = match self.ty {
+            Some(ty) => ty,
+            None => {
+                let Some(ty) = stream::read_varint(connection, self.id, &mut self.buf)? else {
+                    return Ok(None); // Keep trying
+                };
+
+                // A frame type stays in the buffer to be decoded along with the rest of its frame
+                if ty.into_inner() == frame::WEBTRANSPORT_STREAM {
+                    self.buf.clear();
+                }
+
+                *self.ty.insert(ty.into_inner())
+            }
+        };
+
+        if ty == frame::WEBTRANSPORT_STREAM {
+            let session_id = stream::read_varint(connection, self.id, &mut self.buf)?;
+            return Ok(session_id.map(Incoming::WebTransport));
+        }
+
+        // Leave room for the frame header on top of the header block itself
+        let limit = self.max_size + MAX_FRAME_HEADER_SIZE;
+        let finished = stream::read_to(connection, self.id, &mut self.buf, limit)?;
+
+        if let Some(request) = self.decode()? {
+            return Ok(Some(Incoming::Request(request)));
+        }
+
+        if self.buf.len() >= limit {
+            return Err(WebTransportError::HeadersTooLarge);
+        }
+
+        match finished {
+            true => Err(WebTransportError::UnexpectedEnd),
+            false => Ok(None), // Keep trying
+        }
+    }
+
+    /// Decodes the request once its HEADERS frame is complete.
+    ///
+    /// Everything up to and including the HEADERS frame is removed from the buffer, leaving
+    /// whatever the client sent after it.
+    fn decode(&mut self) -> Result<Option<HttpRequest>, WebTransportError> {
+        let mut offset = 0;
+        while let Some((frame, size)) = frame::decode(&self.buf[offset..]) {
+            match frame.ty {
+                frame::HEADERS => {
+                    let request = HttpRequest::decode(frame.payload)?;
+                    self.buf.drain(..offset + size);
+                    return Ok(Some(request));
+                }
+                // Clients never push, and control frames belong on the control stream
+                frame::DATA | frame::PUSH_PROMISE => {
+                    return Err(WebTransportError::UnexpectedFrame(frame.ty));
+                }
+                ty if frame::is_control(ty) || frame::is_reserved_http2(ty) => {
+                    return Err(WebTransportError::UnexpectedFrame(ty));
+                }
+                _ => offset += size, // Unknown frame types must be ignored
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::Side;
+
+    use super::*;
+    use crate::webtransport::error::WEBTRANSPORT_BUFFERED_STREAM_REJECTED;
+    use crate::webtransport::qpack;
+    use crate::webtransport::testing::{self, TestClient};
+
+    fn incoming(buf: Vec<u8>) -> IncomingRequest {
+        IncomingRequest {
+            id: StreamId::new(Side::Client, Dir::Bi, 0),
+            ty: None,
+            buf,
+            max_size: 1024,
+        }
+    }
+
+    fn headers_frame(buf: &mut Vec<u8>) {
+        let fields = [
+            (":method", "CONNECT"),
+            (":protocol", "webtransport"),
+            (":scheme", "https"),
+            (":authority", "localhost"),
+            (":path", "/"),
+        ];
+
+        let mut headers = Vec::new();
+        qpack::encode(fields, &mut headers);
+        frame::encode(frame::HEADERS, &headers, buf);
+    }
+
+    #[test]
+    fn keeps_data_after_headers() {
+        let mut buf = Vec::new();
+        frame::encode(0x21, b"ignored", &mut buf);
+        headers_frame(&mut buf);
+
+        let mut data = Vec::new();
+        frame::encode(frame::DATA, b"capsules", &mut data);
+        buf.extend_from_slice(&data);
+
+        let mut request = incoming(buf);
+        let decoded = request.decode().unwrap().unwrap();
+        assert_eq!(decoded.method, Method::CONNECT);
+        assert_eq!(request.buf, data);
+    }
+
+    #[test]
+    fn waits_for_complete_headers() {
+        let mut buf = Vec::new();
+        headers_frame(&mut buf);
+        let full = buf.len();
+        buf.truncate(full - 1);
+
+        let mut request = incoming(buf);
+        assert!(request.decode().unwrap().is_none());
+        assert_eq!(request.buf.len(), full - 1);
+    }
+
+    /// The start of a WebTransport bidirectional stream, followed by some application data.
+    fn webtransport_stream(session_id: StreamId) -> Vec<u8> {
+        let mut data = Vec::new();
+        frame::encode_varint(frame::WEBTRANSPORT_STREAM, &mut data);
+        frame::encode_varint(VarInt::from(session_id).into_inner(), &mut data);
+        data.extend_from_slice(b"hello");
+        data
+    }
+
+    /// Reads everything the server has received on a stream so far.
+    fn server_read(client: &mut TestClient, id: StreamId) -> Vec<u8> {
+        let mut buf = Vec::new();
+        stream::read_to(&mut client.session().inner, id, &mut buf, usize::MAX).unwrap();
+        buf
+    }
+
+    #[test]
+    fn webtransport_streams_skip_dispatch() {
+        let mut client = TestClient::establish(WebTransportConfig::default());
+        let session_id = StreamId::new(Side::Client, Dir::Bi, 0);
+
+        let id = client.open_bi(&webtransport_stream(session_id));
+        client.drive();
+
+        assert_eq!(client.session().accept_bi(), Some(id));
+        assert!(client.session().request.streams.responses.is_empty());
+        assert_eq!(client.stopped(id), None);
+        assert!(client.read(id).is_empty());
+
+        // Only the header was read, so the application data is still there for the session
+        assert_eq!(server_read(&mut client, id), b"hello");
+    }
+
+    #[test]
+    fn buffers_webtransport_streams_until_connected() {
+        let mut client = TestClient::connect(WebTransportConfig::default());
+        let session_id = StreamId::new(Side::Client, Dir::Bi, 0);
+
+        // Open the CONNECT stream first so it gets the session's stream ID
+        let connect = client.connection.streams().open(Dir::Bi).unwrap();
+        let id = client.open_bi(&webtransport_stream(session_id));
+        client.drive();
+        assert_eq!(client.session().accept_bi(), None);
+
+        let mut data = Vec::new();
+        testing::headers_frame(
+            &[
+                (":method", "CONNECT"),
+                (":protocol", "webtransport"),
+                (":scheme", "https"),
+                (":authority", "localhost"),
+                (":path", "/"),
+            ],
+            &mut data,
+        );
+        client.write(connect, &data);
+        client.open_uni(&testing::client_settings());
+        client.drive();
+
+        assert_eq!(client.session().accept_bi(), Some(id));
+    }
+
+    #[test]
+    fn rejects_streams_for_other_sessions() {
+        let mut client = TestClient::establish(WebTransportConfig::default());
+        let other = StreamId::new(Side::Client, Dir::Bi, 7);
+
+        let id = client.open_bi(&webtransport_stream(other));
+        client.drive();
+
+        assert_eq!(client.session().accept_bi(), None);
+        assert_eq!(
+            client.stopped(id),
+            Some(WEBTRANSPORT_BUFFERED_STREAM_REJECTED)
+        );
+    }
+
+    #[test]
+    fn oversized_headers_get_a_431() {
+        let mut config = WebTransportConfig::default();
+        config.max_header_size = 64;
+        let mut client = TestClient::connect(config);
+
+        let path = format!("/{}", "a".repeat(1000));
+        let mut data = Vec::new();
+        testing::headers_frame(&[(":method", "GET"), (":path", &path)], &mut data);
+        let id = client.open_bi(&data);
+        client.drive();
+
+        let response = client.read(id);
+        assert_eq!(testing::response_status(&response), Some(431));
+        assert_eq!(client.close_code(), None);
+    }
+}
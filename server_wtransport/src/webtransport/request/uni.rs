@@ -0,0 +1,285 @@
+use quinn_proto::{Connection, Dir, StreamId, VarInt};
+
+use crate::webtransport::error::H3_STREAM_CREATION_ERROR;
+use crate::webtransport::settings::H3Settings;
+use crate::webtransport::{WebTransportError, frame, stream};
+
+use super::buffered::BufferedStreams;
+use super::control::{ControlStream, SessionEvent};
+
+/// Accepts the peer's unidirectional streams and routes them by their stream type.
+///
+/// Unknown (and GREASE) stream types are stopped without being read, as RFC 9114 requires.
+/// WebTransport streams are held for the session, up to a limit.
+pub struct UniStreams {
+    pending: Vec<PendingStream>,
+    max_control_frame_size: usize,
+
+    control: Option<ControlStream>,
+    qpack_encoder: Option<StreamId>,
+    qpack_decoder: Option<StreamId>,
+    webtransport: BufferedStreams,
+}
+
+struct PendingStream {
+    id: StreamId,
+    ty: Option<u64>,
+    buf: Vec<u8>,
+}
+
+impl UniStreams {
+    pub fn new(max_control_frame_size: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_control_frame_size,
+            control: None,
+            qpack_encoder: None,
+            qpack_decoder: None,
+            webtransport: BufferedStreams::new(),
+        }
+    }
+
+    /// The peer's SETTINGS, once they've arrived on its control stream.
+    pub fn peer_settings(&self) -> Option<&H3Settings> {
+        self.control.as_ref().and_then(ControlStream::settings)
+    }
+
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.control.as_mut().and_then(ControlStream::poll_event)
+    }
+
+    /// Takes the oldest WebTransport unidirectional stream for the given session, if any.
+    ///
+    /// The stream's header has already been read, so everything left on it is application data.
+    pub fn accept_webtransport(&mut self, session_id: VarInt) -> Option<StreamId> {
+        self.webtransport.accept(session_id)
+    }
+
+    /// Rejects WebTransport streams for any session but ours, now that we know which that is.
+    pub fn reject_other_sessions(&mut self, connection: &mut Connection, session_id: VarInt) {
+        self.webtransport.reject_others(connection, session_id);
+    }
+
+    pub fn update(&mut self, connection: &mut Connection) -> Result<(), WebTransportError> {
+        while let Some(id) = connection.streams().accept(Dir::Uni) {
+            self.pending.push(PendingStream::new(id));
+        }
+
+        let mut index = 0;
+        while index < self.pending.len() {
+            match self.pending[index].update(connection)? {
+                PendingState::Waiting => index += 1,
+                PendingState::Closed => {
+                    // The peer gave up on the stream before telling us what it was for
+                    self.pending.swap_remove(index);
+                }
+                PendingState::Ready(ty, session_id) => {
+                    let id = self.pending.swap_remove(index).id;
+                    self.route(connection, id, ty, session_id)?;
+                }
+            }
+        }
+
+        if let Some(control) = &mut self.control {
+            control.update(connection)?;
+        }
+
+        // We never let the peer use a dynamic table, so there's nothing to do with QPACK
+        // instructions, but both streams must still be drained and kept open
+        for id in self.qpack_encoder.iter().chain(self.qpack_decoder.iter()) {
+            if stream::discard_available(connection, *id)? {
+                return Err(WebTransportError::ClosedCriticalStream);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn route(
+        &mut self,
+        connection: &mut Connection,
+        id: StreamId,
+        ty: u64,
+        session_id: Option<VarInt>,
+    ) -> Result<(), WebTransportError> {
+        let duplicate = match ty {
+            frame::STREAM_CONTROL => self.control.is_some(),
+            frame::STREAM_QPACK_ENCODER => self.qpack_encoder.is_some(),
+            frame::STREAM_QPACK_DECODER => self.qpack_decoder.is_some(),
+            _ => false,
+        };
+
+        // Each critical stream type may only be opened once
+        if duplicate {
+            return Err(WebTransportError::DuplicateStreamType(ty));
+        }
+
+        match ty {
+            frame::STREAM_CONTROL => {
+                self.control = Some(ControlStream::new(id, self.max_control_frame_size));
+            }
+            frame::STREAM_QPACK_ENCODER => self.qpack_encoder = Some(id),
+            frame::STREAM_QPACK_DECODER => self.qpack_decoder = Some(id),
+
+            // Only servers are allowed to push
+            frame::STREAM_PUSH => return Err(WebTransportError::UnexpectedStreamType(ty)),
+
+            frame::STREAM_WEBTRANSPORT => {
+                let session_id = session_id.expect("webtransport stream without session id");
+                self.webtransport.push(connection, session_id, id);
+            }
+
+            _ => {
+                _ = connection.recv_stream(id).stop(H3_STREAM_CREATION_ERROR);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+enum PendingState {
+    Waiting,
+    Closed,
+    Ready(u64, Option<VarInt>),
+}
+
+impl PendingStream {
+    fn new(id: StreamId) -> Self {
+        Self {
+            id,
+            ty: None,
+            buf: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, connection: &mut Connection) -> Result<PendingState, WebTransportError> {
+        let result = self.read_header(connection);
+
+        match result {
+            Ok(Some((ty, session_id))) => Ok(PendingState::Ready(ty, session_id)),
+            Ok(None) => Ok(PendingState::Waiting),
+            Err(WebTransportError::UnexpectedEnd | WebTransportError::ReadError(_)) => {
+                Ok(PendingState::Closed)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the stream type, plus the session id for WebTransport streams.
+    fn read_header(
+        &mut self,
+        connection: &mut Connection,
+    ) -> Result<Option<(u64, Option<VarInt>)>, WebTransportError> {
+        let ty = match self.ty {
+            Some(ty) => ty,
+            None => {
+                let Some(ty) = stream::read_varint(connection, self.id, &mut self.buf)? else {
+                    return Ok(None); // Keep trying
+                };
+
+                self.buf.clear();
+                *self.ty.insert(ty.into_inner())
+            }
+        };
+
+        if ty != frame::STREAM_WEBTRANSPORT {
+            return Ok(Some((ty, None)));
+        }
+
+        let session_id = stream::read_varint(connection, self.id, &mut self.buf)?;
+        Ok(session_id.map(|session_id| (ty, Some(session_id))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn_proto::{Dir, Side};
+
+    use super::*;
+    use crate::webtransport::WebTransportConfig;
+    use crate::webtransport::error::WEBTRANSPORT_BUFFERED_STREAM_REJECTED;
+    use crate::webtransport::request::buffered::MAX_BUFFERED_STREAMS;
+    use crate::webtransport::testing::{self, TestClient};
+
+    fn stream_type(ty: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        frame::encode_varint(ty, &mut data);
+        data
+    }
+
+    fn webtransport_stream(session_id: u64) -> Vec<u8> {
+        let mut data = stream_type(frame::STREAM_WEBTRANSPORT);
+        frame::encode_varint(session_id, &mut data);
+        data.extend_from_slice(b"hello");
+        data
+    }
+
+    #[test]
+    fn duplicate_control_streams_are_rejected() {
+        let mut client = TestClient::connect(WebTransportConfig::default());
+        client.open_uni(&testing::client_settings());
+        client.open_uni(&testing::client_settings());
+        client.drive();
+
+        assert_eq!(client.close_code(), Some(H3_STREAM_CREATION_ERROR));
+    }
+
+    #[test]
+    fn duplicate_qpack_streams_are_rejected() {
+        for ty in [frame::STREAM_QPACK_ENCODER, frame::STREAM_QPACK_DECODER] {
+            let mut client = TestClient::connect(WebTransportConfig::default());
+            client.open_uni(&stream_type(ty));
+            client.open_uni(&stream_type(ty));
+            client.drive();
+
+            assert_eq!(client.close_code(), Some(H3_STREAM_CREATION_ERROR));
+        }
+    }
+
+    #[test]
+    fn unknown_streams_are_stopped() {
+        let mut client = TestClient::connect(WebTransportConfig::default());
+        let unknown = client.open_uni(&stream_type(0x1234));
+        let grease = client.open_uni(&stream_type(frame::grease()));
+        client.drive();
+
+        assert_eq!(client.stopped(unknown), Some(H3_STREAM_CREATION_ERROR));
+        assert_eq!(client.stopped(grease), Some(H3_STREAM_CREATION_ERROR));
+        assert_eq!(client.close_code(), None);
+    }
+
+    #[test]
+    fn rejects_streams_for_other_sessions() {
+        let mut client = TestClient::establish(WebTransportConfig::default());
+        let session_id = VarInt::from(StreamId::new(Side::Client, Dir::Bi, 0));
+        let other = VarInt::from(StreamId::new(Side::Client, Dir::Bi, 7));
+
+        let ours = client.open_uni(&webtransport_stream(session_id.into_inner()));
+        let theirs = client.open_uni(&webtransport_stream(other.into_inner()));
+        client.drive();
+
+        assert_eq!(client.session().accept_uni(), Some(ours));
+        assert_eq!(client.session().accept_uni(), None);
+        assert_eq!(client.stopped(ours), None);
+        assert_eq!(
+            client.stopped(theirs),
+            Some(WEBTRANSPORT_BUFFERED_STREAM_REJECTED)
+        );
+    }
+
+    #[test]
+    fn bounds_streams_buffered_before_the_session() {
+        let mut client = TestClient::connect(WebTransportConfig::default());
+        let ids: Vec<_> = (0..MAX_BUFFERED_STREAMS + 1)
+            .map(|_| client.open_uni(&webtransport_stream(0)))
+            .collect();
+        client.drive();
+
+        let rejected = ids
+            .iter()
+            .filter(|&&id| client.stopped(id).is_some())
+            .count();
+        assert_eq!(rejected, 1);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use quinn_proto::VarInt;
+use quinn_proto::coding::Codec;
+
+use crate::webtransport::{WebTransportError, frame};
+
+// Setting identifiers (RFC 9114, RFC 9220, RFC 9297 and the WebTransport over HTTP/3 drafts).
+pub const MAX_FIELD_SECTION_SIZE: u64 = 0x06;
+pub const ENABLE_CONNECT_PROTOCOL: u64 = 0x08;
+pub const H3_DATAGRAM: u64 = 0x33;
+pub const H3_DATAGRAM_DEPRECATED: u64 = 0xffd277;
+pub const WEBTRANSPORT_ENABLE_DEPRECATED: u64 = 0x2b603742;
+pub const WEBTRANSPORT_MAX_SESSIONS_DEPRECATED: u64 = 0x2b603743;
+pub const WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671706a;
+
+/// The WebTransport over HTTP/3 drafts we understand, which differ in how they're negotiated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WebTransportDraft {
+    /// Enabled by a boolean SETTINGS_ENABLE_WEBTRANSPORT, and answered with a draft header.
+    Draft02,
+    /// Enabled by SETTINGS_WEBTRANSPORT_MAX_SESSIONS under its original identifier.
+    Draft07,
+    /// Enabled by SETTINGS_WT_MAX_SESSIONS under its current identifier.
+    Latest,
+}
+
+impl WebTransportDraft {
+    /// Every draft, from oldest to newest.
+    pub const ALL: [WebTransportDraft; 3] = [Self::Draft02, Self::Draft07, Self::Latest];
+}
+
+/// The contents of an HTTP/3 SETTINGS frame, ordered by identifier.
+#[derive(Debug, Clone, Default)]
+pub struct H3Settings(BTreeMap<u64, u64>);
+
+impl H3Settings {
+    pub fn decode(mut payload: &[u8]) -> Result<Self, WebTransportError> {
+        let mut settings = BTreeMap::new();
+
+        while payload.is_empty() == false {
+            let id = VarInt::decode(&mut payload)
+                .map_err(|_| WebTransportError::InvalidSettings)?
+                .into_inner();
+            let value = VarInt::decode(&mut payload)
+                .map_err(|_| WebTransportError::InvalidSettings)?
+                .into_inner();
+
+            // Reserved HTTP/2 settings are forbidden, as are duplicates
+            if (0x02..=0x05).contains(&id) || settings.insert(id, value).is_some() {
+                return Err(WebTransportError::InvalidSettings);
+            }
+        }
+
+        Ok(Self(settings))
+    }
+
+    pub fn get(&self, id: u64) -> Option<u64> {
+        self.0.get(&id).copied()
+    }
+
+    pub fn insert(&mut self, id: u64, value: u64) {
+        self.0.insert(id, value);
+    }
+
+    /// Encodes the control stream header followed by the SETTINGS frame.
+    ///
+    /// Settings are written in identifier order, so the same settings always encode identically.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        for (&id, &value) in &self.0 {
+            frame::encode_varint(id, &mut payload);
+            frame::encode_varint(value, &mut payload);
+        }
+
+        frame::encode_varint(frame::STREAM_CONTROL, buf);
+        frame::encode(frame::SETTINGS, &payload, buf);
+    }
+
+    /// Whether the peer supports extended CONNECT (RFC 9220).
+    pub fn supports_extended_connect(&self) -> bool {
+        self.get(ENABLE_CONNECT_PROTOCOL) == Some(1)
+    }
+
+    /// Whether the peer accepts HTTP datagrams (RFC 9297), under either the final or draft setting.
+    pub fn supports_h3_datagram(&self) -> bool {
+        self.get(H3_DATAGRAM) == Some(1) || self.get(H3_DATAGRAM_DEPRECATED) == Some(1)
+    }
+
+    /// Returns the number of WebTransport sessions the peer supports under the given draft.
+    ///
+    /// Zero means the peer doesn't support that draft at all.
+    pub fn webtransport_max_sessions(&self, draft: WebTransportDraft) -> u64 {
+        match draft {
+            // This draft used a boolean rather than a session limit
+            WebTransportDraft::Draft02 => match self.get(WEBTRANSPORT_ENABLE_DEPRECATED) {
+                Some(1) => 1,
+                _ => 0,
+            },
+            WebTransportDraft::Draft07 => {
+                self.get(WEBTRANSPORT_MAX_SESSIONS_DEPRECATED).unwrap_or(0)
+            }
+            WebTransportDraft::Latest => self.get(WEBTRANSPORT_MAX_SESSIONS).unwrap_or(0),
+        }
+    }
+
+    /// Picks the newest of our drafts that the peer supports as well.
+    pub fn negotiate_draft(&self, ours: &[WebTransportDraft]) -> Option<WebTransportDraft> {
+        WebTransportDraft::ALL
+            .into_iter()
+            .rev()
+            .filter(|draft| ours.contains(draft))
+            .find(|&draft| self.webtransport_max_sessions(draft) > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use WebTransportDraft::{Draft02, Draft07, Latest};
+
+    fn peer(settings: &[(u64, u64)]) -> H3Settings {
+        let mut peer = H3Settings::default();
+        for &(id, value) in settings {
+            peer.insert(id, value);
+        }
+        peer
+    }
+
+    #[test]
+    fn negotiates_the_newest_common_draft() {
+        let draft02 = (WEBTRANSPORT_ENABLE_DEPRECATED, 1);
+        let draft07 = (WEBTRANSPORT_MAX_SESSIONS_DEPRECATED, 4);
+        let latest = (WEBTRANSPORT_MAX_SESSIONS, 4);
+
+        let all = WebTransportDraft::ALL;
+        assert_eq!(peer(&[draft02]).negotiate_draft(&all), Some(Draft02));
+        assert_eq!(peer(&[draft07]).negotiate_draft(&all), Some(Draft07));
+        assert_eq!(peer(&[latest]).negotiate_draft(&all), Some(Latest));
+        assert_eq!(
+            peer(&[draft02, draft07]).negotiate_draft(&all),
+            Some(Draft07)
+        );
+        assert_eq!(
+            peer(&[draft02, draft07, latest]).negotiate_draft(&all),
+            Some(Latest)
+        );
+
+        // Only drafts we offer ourselves are considered
+        let everything = peer(&[draft02, draft07, latest]);
+        assert_eq!(
+            everything.negotiate_draft(&[Draft02, Draft07]),
+            Some(Draft07)
+        );
+        assert_eq!(everything.negotiate_draft(&[Draft02]), Some(Draft02));
+        assert_eq!(peer(&[draft07]).negotiate_draft(&[Draft02, Latest]), None);
+        assert_eq!(everything.negotiate_draft(&[]), None);
+    }
+
+    #[test]
+    fn disabled_drafts_are_not_negotiated() {
+        let disabled = peer(&[
+            (WEBTRANSPORT_ENABLE_DEPRECATED, 0),
+            (WEBTRANSPORT_MAX_SESSIONS_DEPRECATED, 0),
+            (WEBTRANSPORT_MAX_SESSIONS, 0),
+        ]);
+        assert_eq!(disabled.negotiate_draft(&WebTransportDraft::ALL), None);
+
+        // Draft02 used a boolean, so anything but 1 doesn't enable it
+        let not_boolean = peer(&[(WEBTRANSPORT_ENABLE_DEPRECATED, 2)]);
+        assert_eq!(not_boolean.negotiate_draft(&WebTransportDraft::ALL), None);
+    }
+}
@@ -0,0 +1,133 @@
+use quinn_proto::coding::Codec;
+use quinn_proto::{Connection, ReadError, StreamId, VarInt, WriteError};
+
+use crate::webtransport::WebTransportError;
+
+/// Data queued for a send stream, which is finished once everything has been written.
+pub struct PendingWrite {
+    id: StreamId,
+    data: Vec<u8>,
+    written: usize,
+}
+
+/// Reads from a stream into `buf` until it holds `len` bytes, without reading any further.
+///
+/// Returns `true` once the peer has finished the stream and all of its data has been read.
+pub fn read_to(
+    connection: &mut Connection,
+    id: StreamId,
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> Result<bool, WebTransportError> {
+    let mut recv_stream = connection.recv_stream(id);
+    let mut chunks = recv_stream
+        .read(true)
+        .map_err(|_| WebTransportError::UnexpectedEnd)?;
+
+    let finished = loop {
+        if buf.len() >= len {
+            break false;
+        }
+
+        match chunks.next(len - buf.len()) {
+            Ok(Some(chunk)) => buf.extend_from_slice(&chunk.bytes),
+            Ok(None) => break true,
+            Err(ReadError::Blocked) => break false,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    // Any flow control credit we issue goes out with the next poll_transmit
+    _ = chunks.finalize();
+    Ok(finished)
+}
+
+/// Reads a single varint from a stream, leaving everything after it unread.
+///
+/// The bytes of a partially read varint are kept in `buf`, which must be passed back in as-is.
+pub fn read_varint(
+    connection: &mut Connection,
+    id: StreamId,
+    buf: &mut Vec<u8>,
+) -> Result<Option<VarInt>, WebTransportError> {
+    loop {
+        // The two high bits of the first byte encode the varint's total length
+        let len = buf.first().map_or(1, |first| 1 << (first >> 6));
+        if buf.len() >= len {
+            return Ok(Some(VarInt::decode(&mut &buf[..])?));
+        }
+
+        let before = buf.len();
+        let finished = read_to(connection, id, buf, len)?;
+
+        if buf.len() == before {
+            return match finished {
+                true => Err(WebTransportError::UnexpectedEnd),
+                false => Ok(None), // Keep trying
+            };
+        }
+    }
+}
+
+/// Reads and throws away everything currently available on a stream.
+///
+/// Returns `true` once the peer has finished the stream.
+pub fn discard_available(
+    connection: &mut Connection,
+    id: StreamId,
+) -> Result<bool, WebTransportError> {
+    let mut recv_stream = connection.recv_stream(id);
+    let mut chunks = recv_stream
+        .read(false)
+        .map_err(|_| WebTransportError::UnexpectedEnd)?;
+
+    let finished = loop {
+        match chunks.next(usize::MAX) {
+            Ok(Some(_)) => {}
+            Ok(None) => break true,
+            Err(ReadError::Blocked) => break false,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    _ = chunks.finalize();
+    Ok(finished)
+}
+
+/// Writes as much of `data` as the stream currently allows, returning the number of bytes written.
+pub fn write_some(
+    connection: &mut Connection,
+    id: StreamId,
+    data: &[u8],
+) -> Result<usize, WebTransportError> {
+    match connection.send_stream(id).write(data) {
+        Ok(written) => Ok(written),
+        Err(WriteError::Blocked) => Ok(0), // Flow control will open up again later
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl PendingWrite {
+    pub fn new(id: StreamId, data: Vec<u8>) -> Self {
+        Self {
+            id,
+            data,
+            written: 0,
+        }
+    }
+
+    /// Writes as much as possible, returning `true` once the stream has been finished.
+    pub fn update(&mut self, connection: &mut Connection) -> Result<bool, WebTransportError> {
+        if self.written < self.data.len() {
+            self.written += write_some(connection, self.id, &self.data[self.written..])?;
+        }
+
+        if self.written < self.data.len() {
+            return Ok(false); // Keep trying
+        }
+
+        // If the peer already stopped the stream there's nothing left for us to do anyway
+        _ = connection.send_stream(self.id).finish();
+        Ok(true)
+    }
+}